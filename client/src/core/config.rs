@@ -0,0 +1,293 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use ratatui::style::Color;
+use serde::Deserialize;
+use rustls::{
+    ClientConfig, RootCertStore,
+    pki_types::{CertificateDer, pem::PemObject},
+};
+
+// Entries kept in the message pane unless the config file says otherwise
+const DEFAULT_MAX_MESSAGES: usize = 5000;
+
+// Colors of the parts of the UI that aren't tied to a user
+#[derive(Clone, Copy)]
+pub struct Colors {
+    pub border: Color,
+    pub timestamp: Color,
+    pub system: Color,
+    pub whisper: Color,
+    // Text of our own messages, and everyone else's
+    pub own: Color,
+    pub text: Color,
+    // Failed sends, lost connections and login problems
+    pub error: Color,
+}
+
+impl Default for Colors {
+    fn default() -> Self {
+        Theme::default().colors()
+    }
+}
+
+// Built-in sets of colors, picked with `theme` in the config file or /theme
+#[derive(Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Theme {
+    #[default]
+    Dark,
+    // For terminals with a light background, where gray and light colors
+    // are hard to read
+    Light,
+}
+
+impl Theme {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "dark" => Some(Theme::Dark),
+            "light" => Some(Theme::Light),
+            _ => None,
+        }
+    }
+
+    pub fn colors(self) -> Colors {
+        match self {
+            Theme::Dark => Colors {
+                border: Color::Reset,
+                timestamp: Color::DarkGray,
+                system: Color::Gray,
+                whisper: Color::Magenta,
+                own: Color::LightCyan,
+                text: Color::Reset,
+                error: Color::Red,
+            },
+            Theme::Light => Colors {
+                border: Color::Reset,
+                timestamp: Color::DarkGray,
+                system: Color::DarkGray,
+                whisper: Color::Magenta,
+                own: Color::Blue,
+                text: Color::Reset,
+                error: Color::Red,
+            },
+        }
+    }
+}
+
+// Colors set in the config file, each replacing the theme's. Any color
+// ratatui understands works, e.g. "red", "lightblue" or "#ff8800".
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ColorOverrides {
+    border: Option<Color>,
+    timestamp: Option<Color>,
+    system: Option<Color>,
+    whisper: Option<Color>,
+    own: Option<Color>,
+    text: Option<Color>,
+    error: Option<Color>,
+}
+
+impl ColorOverrides {
+    fn apply(&self, colors: Colors) -> Colors {
+        Colors {
+            border: self.border.unwrap_or(colors.border),
+            timestamp: self.timestamp.unwrap_or(colors.timestamp),
+            system: self.system.unwrap_or(colors.system),
+            whisper: self.whisper.unwrap_or(colors.whisper),
+            own: self.own.unwrap_or(colors.own),
+            text: self.text.unwrap_or(colors.text),
+            error: self.error.unwrap_or(colors.error),
+        }
+    }
+}
+
+// When to show desktop notifications for mentions and whispers
+#[derive(Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Notifications {
+    // Only while the terminal isn't focused
+    #[default]
+    Unfocused,
+    Always,
+    Off,
+}
+
+// Contents of the config file. Everything is optional.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    server: String,
+    username: String,
+    download_dir: Option<PathBuf>,
+    notifications: Notifications,
+    right_align_own: bool,
+    max_messages: Option<usize>,
+    theme: Theme,
+    colors: ColorOverrides,
+    macros: HashMap<String, String>,
+}
+
+// Client settings collected from the config file and the command line
+#[derive(Default)]
+pub struct Config {
+    // Used to connect over TLS. Connections are plaintext when unset.
+    pub tls: Option<Arc<ClientConfig>>,
+
+    // Prefilled on the login screen, and used to log in straight away
+    // in headless mode
+    pub server: String,
+    pub username: String,
+    pub password: String,
+
+    // Read messages from stdin and print what arrives instead of running
+    // the terminal UI
+    pub headless: bool,
+
+    pub colors: Colors,
+
+    // Draw our own messages against the right edge of the message pane
+    pub right_align_own: bool,
+
+    // Extra `/name` macros and the text they stand for
+    pub macros: HashMap<String, String>,
+
+    // Entries kept in the message pane of each tab. Older ones are dropped,
+    // and nothing is when unset.
+    pub max_messages: Option<usize>,
+
+    // Where files sent to us are saved
+    pub download_dir: PathBuf,
+
+    pub notifications: Notifications,
+
+    // Why the config file couldn't be used, shown on the login screen
+    pub error: Option<String>,
+}
+
+impl Config {
+    pub fn from_args() -> io::Result<Self> {
+        let mut config = Config {
+            max_messages: Some(DEFAULT_MAX_MESSAGES),
+            ..Default::default()
+        };
+        let mut args = env::args().skip(1);
+        let mut tls = false;
+        let mut ca = None;
+        let mut config_path = default_config_path();
+        let mut server = None;
+        let mut username = None;
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--tls" => tls = true,
+                // Trusting a CA only makes sense over TLS, so it implies --tls
+                "--ca" => {
+                    ca = Some(next_value(&mut args, &arg)?);
+                    tls = true;
+                },
+                "--config" => config_path = Some(PathBuf::from(next_value(&mut args, &arg)?)),
+                "--server" => server = Some(next_value(&mut args, &arg)?),
+                "--username" => username = Some(next_value(&mut args, &arg)?),
+                "--password" => config.password = next_value(&mut args, &arg)?,
+                "--headless" => config.headless = true,
+                _ => return Err(invalid_input(format!("Unknown argument: {}", arg))),
+            }
+        }
+
+        // A broken config file shouldn't stop the client from starting
+        if let Some(path) = config_path {
+            match load_file(&path) {
+                Ok(file) => {
+                    config.server = file.server;
+                    config.username = file.username;
+                    config.download_dir = file.download_dir.unwrap_or_default();
+                    config.notifications = file.notifications;
+                    config.colors = file.colors.apply(file.theme.colors());
+                    config.right_align_own = file.right_align_own;
+                    config.macros = file.macros;
+                    if let Some(max) = file.max_messages {
+                        config.max_messages = (max > 0).then_some(max);
+                    }
+                },
+                Err(error) => config.error = Some(error),
+            }
+        }
+
+        // The command line wins over the config file
+        if let Some(server) = server {
+            config.server = server;
+        }
+        if let Some(username) = username {
+            config.username = username;
+        }
+
+        if config.download_dir.as_os_str().is_empty() {
+            config.download_dir = default_download_dir();
+        }
+
+        if tls {
+            config.tls = Some(load_tls(ca.as_deref().map(Path::new))?);
+        }
+
+        Ok(config)
+    }
+}
+
+// ~/.config/rust-chat/config.toml, or under $XDG_CONFIG_HOME when set
+fn default_config_path() -> Option<PathBuf> {
+    let config_dir = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(config_dir.join("rust-chat").join("config.toml"))
+}
+
+// ~/Downloads, or the working directory if there is no home
+fn default_download_dir() -> PathBuf {
+    env::var_os("HOME").map_or_else(|| PathBuf::from("."), |home| PathBuf::from(home).join("Downloads"))
+}
+
+// A missing file just means the defaults are used
+fn load_file(path: &Path) -> Result<FileConfig, String> {
+    match fs::read_to_string(path) {
+        Ok(data) => toml::from_str(&data)
+            .map_err(|error| format!("Invalid config file {}: {}", path.display(), error.message())),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(FileConfig::default()),
+        Err(error) => Err(format!("Failed to read config file {}: {}", path.display(), error)),
+    }
+}
+
+fn next_value(args: &mut impl Iterator<Item = String>, flag: &str) -> io::Result<String> {
+    args.next()
+        .ok_or_else(|| invalid_input(format!("Missing value for {}", flag)))
+}
+
+fn invalid_input(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+// Servers are verified against the bundled web roots, plus the PEM
+// certificates in `ca` when given (e.g. for a self-signed server)
+fn load_tls(ca: Option<&Path>) -> io::Result<Arc<ClientConfig>> {
+    let mut roots = RootCertStore::empty();
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+
+    if let Some(path) = ca {
+        let certs = CertificateDer::pem_file_iter(path)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|error| invalid_input(format!("Invalid CA file {}: {}", path.display(), error)))?;
+        for cert in certs {
+            roots.add(cert)
+                .map_err(|error| invalid_input(format!("Invalid CA file {}: {}", path.display(), error)))?;
+        }
+    }
+
+    let client_config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(Arc::new(client_config))
+}
@@ -0,0 +1,287 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use std::process;
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread;
+use std::time::Duration;
+
+use crate::core::config::Config;
+use crate::core::emoji;
+use crate::core::login;
+use crate::core::macros;
+use crate::core::net::{
+    self, ChatEntry, ClientState, Message, Packet, PacketType, Server, SharedWriter, format_time,
+    write_packet,
+};
+
+// How often new entries are checked for while waiting on stdin
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// Run without the terminal UI: lines read from stdin are sent as messages
+// or commands, and everything received is printed to stdout
+pub fn run(config: Config) -> io::Result<()> {
+    if let Some(error) = &config.error {
+        eprintln!("Warning: {}", error);
+    }
+    let username = net::validate_name(&config.username)
+        .map_err(|reason| io::Error::new(io::ErrorKind::InvalidInput, reason))?;
+    let server = Server {
+        address: login::server_address(&config.server),
+        tls: config.tls.clone(),
+        password: config.password.clone(),
+    };
+    let (uid, reader, writer) = server.connect(&username)?;
+
+    let state = Arc::new(Mutex::new(ClientState::default()));
+    {
+        let mut s = state.lock().unwrap();
+        s.users.insert(uid, username.clone());
+        s.uid = uid;
+        s.username = username;
+        s.download_dir = config.download_dir.clone();
+        s.notifications = config.notifications;
+        s.max_messages = config.max_messages;
+    }
+    let writer = Arc::new(Mutex::new(writer));
+    {
+        let writer = writer.clone();
+        let state = state.clone();
+        thread::spawn(move || net::server_listen(server, reader, writer, state));
+    }
+
+    // Reading stdin blocks, so it gets its own thread. The channel closes
+    // at end of input.
+    let (lines_sender, lines) = mpsc::channel();
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            if lines_sender.send(line).is_err() {
+                break;
+            }
+        }
+    });
+
+    let macros = macros::table(&config.macros);
+    let mut printed: usize = 0;
+    let mut dropped = 0;
+    loop {
+        match lines.recv_timeout(POLL_INTERVAL) {
+            Ok(line) => handle_line(&line, &writer, &state, &macros),
+            Err(mpsc::RecvTimeoutError::Timeout) => (),
+            Err(mpsc::RecvTimeoutError::Disconnected) => quit(&writer, &state, ""),
+        }
+
+        let mut s = state.lock().unwrap();
+        s.expire_ping();
+        // Entries dropped to stay under the cap were printed first
+        printed = printed.saturating_sub(s.dropped - dropped);
+        dropped = s.dropped;
+        // Joining a room replaces the messages with its history
+        if printed > s.messages.len() {
+            printed = 0;
+        }
+        let mut stdout = io::stdout().lock();
+        for entry in &s.messages[printed..] {
+            if let Some(line) = entry_line(entry, &s) {
+                writeln!(stdout, "{}", line)?;
+            }
+        }
+        stdout.flush()?;
+        printed = s.messages.len();
+
+        if s.shutdown || s.closed {
+            return Ok(());
+        }
+    }
+}
+
+// An entry as a line of plain text. Our own chat messages aren't echoed.
+fn entry_line(entry: &ChatEntry, s: &ClientState) -> Option<String> {
+    let line = match entry {
+        ChatEntry::Message { sender_id, .. } if *sender_id == s.uid => return None,
+        _ if s.is_muted(entry) => return None,
+        ChatEntry::Message { uid, sender_id, sender_name, timestamp, text, action, .. } => {
+            let name = s.users.get(sender_id).unwrap_or(sender_name);
+            if *action {
+                format!("[{}] #{} * {} {}", format_time(*timestamp), uid, name, text)
+            }
+            else {
+                format!("[{}] #{} {}: {}", format_time(*timestamp), uid, name, text)
+            }
+        },
+        ChatEntry::Whisper { sender_id, target_id, sender_name, timestamp, text } => {
+            if *sender_id == s.uid {
+                let target = s.users.get(target_id).cloned().unwrap_or_else(|| target_id.to_string());
+                format!("[{}] [DM to {}] {}", format_time(*timestamp), target, text)
+            }
+            else {
+                format!("[{}] [DM from {}] {}", format_time(*timestamp), sender_name, text)
+            }
+        },
+        ChatEntry::System(text) => format!("— {}", text),
+        ChatEntry::Notice(text) | ChatEntry::Motd(text) => text.clone(),
+        ChatEntry::Transfer(transfer) => transfer.summary(),
+    };
+    Some(line)
+}
+
+// Send a line from stdin. Commands that only make sense in the terminal
+// UI are turned down.
+fn handle_line(line: &str, writer: &SharedWriter, state: &Mutex<ClientState>, macros: &HashMap<String, String>) {
+    let uid = state.lock().unwrap().uid;
+    let chat = |contents: &str| Packet {
+        packet_type: PacketType::NewMessage,
+        user_id: uid,
+        contents: emoji::expand(contents),
+        ..Default::default()
+    };
+
+    let (command, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let rest = rest.trim();
+    let packet = match command {
+        "" => return,
+        _ if line.starts_with("//") => chat(&line[1..]),
+        "/quit" => quit(writer, state, rest),
+        "/raw" if !rest.is_empty() => chat(rest),
+        "/me" if !rest.is_empty() => Packet {
+            message: Some(Message {
+                action: true,
+                ..Default::default()
+            }),
+            ..chat(&macros::expand(rest, macros))
+        },
+        "/name" | "/nick" if !rest.is_empty() => match net::validate_name(rest) {
+            Ok(name) => Packet {
+                packet_type: PacketType::UsernameChange,
+                user_id: uid,
+                contents: name,
+                ..Default::default()
+            },
+            Err(reason) => return eprintln!("Error: {}", reason),
+        },
+        "/join" if !rest.is_empty() => Packet {
+            packet_type: PacketType::JoinRoom,
+            user_id: uid,
+            room: rest.to_string(),
+            ..Default::default()
+        },
+        "/whisper" | "/w" if rest.contains(char::is_whitespace) => {
+            let (name, text) = rest.split_once(char::is_whitespace).unwrap_or_default();
+            let Some(target) = find_user(state, name) else {
+                return eprintln!("Error: No user named {}", name);
+            };
+            Packet {
+                packet_type: PacketType::PrivateMessage,
+                user_id: uid,
+                contents: text.trim().to_string(),
+                target,
+                ..Default::default()
+            }
+        },
+        "/whois" if !rest.is_empty() => Packet {
+            packet_type: PacketType::WhoisRequest,
+            user_id: uid,
+            contents: rest.to_string(),
+            ..Default::default()
+        },
+        "/grep" if !rest.is_empty() => Packet {
+            packet_type: PacketType::Grep,
+            user_id: uid,
+            contents: rest.to_string(),
+            ..Default::default()
+        },
+        "/away" => Packet {
+            packet_type: PacketType::StatusChange,
+            user_id: uid,
+            contents: if rest.is_empty() { String::from("Away from keyboard") } else { rest.to_string() },
+            ..Default::default()
+        },
+        "/back" => Packet {
+            packet_type: PacketType::StatusChange,
+            user_id: uid,
+            ..Default::default()
+        },
+        "/roll" => Packet {
+            packet_type: PacketType::DiceRoll,
+            user_id: uid,
+            contents: if rest.is_empty() { String::from("1d6") } else { rest.to_string() },
+            ..Default::default()
+        },
+        "/color" => Packet {
+            packet_type: PacketType::ColorChange,
+            user_id: uid,
+            contents: rest.to_string(),
+            ..Default::default()
+        },
+        "/mute" | "/unmute" if !rest.is_empty() => {
+            let Some(target) = find_user(state, rest) else {
+                return eprintln!("Error: No user named {}", rest);
+            };
+            let mut s = state.lock().unwrap();
+            if command == "/mute" {
+                s.muted.insert(target);
+                println!("Muted {}", rest);
+            }
+            else {
+                s.muted.remove(&target);
+                println!("Unmuted {}", rest);
+            }
+            return;
+        },
+        "/ping" => state.lock().unwrap().start_ping(),
+        "/stats" => {
+            let target = match rest {
+                "" => uid,
+                name => match find_user(state, name) {
+                    Some(target) => target,
+                    None => return eprintln!("Error: No user named {}", name),
+                },
+            };
+            Packet {
+                packet_type: PacketType::Stats,
+                user_id: uid,
+                target,
+                ..Default::default()
+            }
+        },
+        "/list" => {
+            let s = state.lock().unwrap();
+            for (uid, name) in s.sorted_users() {
+                match s.idle_for(uid) {
+                    Some(idle) => println!("{} ({}, idle {})", name, uid, net::format_idle(idle)),
+                    None => println!("{} ({})", name, uid),
+                }
+            }
+            return;
+        },
+        _ if command.starts_with('/') && macros.contains_key(&command[1..]) => chat(&macros::expand(line, macros)),
+        _ if command.starts_with('/') => {
+            return eprintln!("Error: {} isn't available in headless mode", command);
+        },
+        _ => chat(&macros::expand(line, macros)),
+    };
+
+    if let Err(error) = write_packet(&mut *writer.lock().unwrap(), &packet) {
+        eprintln!("Error: Failed to send: {}", error);
+    }
+}
+
+fn find_user(state: &Mutex<ClientState>, name: &str) -> Option<u32> {
+    state.lock().unwrap().users
+        .iter()
+        .find(|(_, user)| user.as_str() == name)
+        .map(|(uid, _)| *uid)
+}
+
+fn quit(writer: &SharedWriter, state: &Mutex<ClientState>, message: &str) -> ! {
+    let packet = Packet {
+        packet_type: PacketType::UserDisconnected,
+        user_id: state.lock().unwrap().uid,
+        contents: message.to_string(),
+        ..Default::default()
+    };
+    let _ = write_packet(&mut *writer.lock().unwrap(), &packet);
+    process::exit(0);
+}
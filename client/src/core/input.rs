@@ -0,0 +1,66 @@
+// Editing helpers shared by the login and chat inputs. Positions are
+// counted in chars, like the inputs' `character_index`.
+
+// Start of the word before the cursor, skipping any spaces in between
+pub fn word_left(text: &str, index: usize) -> usize {
+    let chars: Vec<char> = text.chars().take(index).collect();
+    let mut pos = chars.len();
+    while pos > 0 && chars[pos - 1].is_whitespace() {
+        pos -= 1;
+    }
+    while pos > 0 && !chars[pos - 1].is_whitespace() {
+        pos -= 1;
+    }
+    pos
+}
+
+// End of the word after the cursor, skipping any spaces in between
+pub fn word_right(text: &str, index: usize) -> usize {
+    let chars: Vec<char> = text.chars().collect();
+    let mut pos = index.min(chars.len());
+    while pos < chars.len() && chars[pos].is_whitespace() {
+        pos += 1;
+    }
+    while pos < chars.len() && !chars[pos].is_whitespace() {
+        pos += 1;
+    }
+    pos
+}
+
+// Remove the word before the cursor, returning the new cursor position
+pub fn delete_word(text: &mut String, index: usize) -> usize {
+    let start = word_left(text, index);
+    *text = text
+        .chars()
+        .take(start)
+        .chain(text.chars().skip(index))
+        .collect();
+    start
+}
+
+// Start of the word the cursor is at the end of, or the cursor itself
+// right after a space
+pub fn word_start(text: &str, index: usize) -> usize {
+    let chars: Vec<char> = text.chars().take(index).collect();
+    let mut pos = chars.len();
+    while pos > 0 && !chars[pos - 1].is_whitespace() {
+        pos -= 1;
+    }
+    pos
+}
+
+// Longest start every name shares, ignoring case, spelled as in the first
+pub fn common_prefix(names: &[String]) -> String {
+    let Some((first, rest)) = names.split_first() else {
+        return String::new();
+    };
+    let mut length = first.chars().count();
+    for name in rest {
+        length = first.chars()
+            .zip(name.chars())
+            .take(length)
+            .take_while(|(a, b)| a.to_lowercase().eq(b.to_lowercase()))
+            .count();
+    }
+    first.chars().take(length).collect()
+}
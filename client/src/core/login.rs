@@ -1,49 +1,88 @@
 use std::process;
 use std::time::Duration;
-use std::io::{self, BufReader, BufWriter, Write};
-use std::net::{TcpStream};
-use serde::{Deserialize};
+use std::io;
+use std::net::Ipv6Addr;
+use std::sync::Arc;
+use rustls::ClientConfig;
 use ratatui::{
-    crossterm::event::{self, Event, KeyCode},
+    crossterm::event::{self, Event, KeyCode, KeyModifiers},
     layout::{Constraint, Layout,},
-    style::{Style},
+    style::{Style, Stylize},
     widgets::{Block, Paragraph,},
     DefaultTerminal, Frame,
 };
 
-use crate::core::net::{Packet, PacketType};
+use crate::core::config::{Colors, Config};
+use crate::core::input;
+use crate::core::ui;
+use crate::core::net::{Reader, Server, Writer, validate_name};
+
+const DEFAULT_HOST: &str = "127.0.0.1";
+const DEFAULT_PORT: u16 = 8080;
+
+// Smallest terminal whose middle fits all three inputs and the status line
+const MIN_WIDTH: u16 = 40;
+const MIN_HEIGHT: u16 = 13;
+
+// Start of the links /invite hands out, followed by `host:port`
+pub const LINK_SCHEME: &str = "rustchat://";
+
+// Turn the address typed by the user into a `host:port` pair,
+// filling in defaults for whatever was left out. Invite links work too.
+// IPv6 addresses go in brackets when a port follows, like `[::1]:8080`.
+pub fn server_address(input: &str) -> String {
+    let input = input.trim();
+    let input = input.strip_prefix(LINK_SCHEME).map_or(input, |rest| rest.trim_end_matches('/'));
+    match input {
+        "" => format!("{}:{}", DEFAULT_HOST, DEFAULT_PORT),
+        // A bare IPv6 address is all colons, so it can't have a port yet
+        _ if input.parse::<Ipv6Addr>().is_ok() => format!("[{}]:{}", input, DEFAULT_PORT),
+        _ if input.starts_with('[') && !input.contains("]:") => format!("{}:{}", input, DEFAULT_PORT),
+        _ if input.contains(':') => input.to_string(),
+        _ => format!("{}:{}", input, DEFAULT_PORT),
+    }
+}
 
 pub struct Login {
     address_input: String,
     username_input: String,
+    password_input: String,
     character_index: usize,
     input_select: u8,
 
-    reader: Option<BufReader<TcpStream>>,
-    writer: Option<BufWriter<TcpStream>>,
+    server: Option<Server>,
+    reader: Option<Reader>,
+    writer: Option<Writer>,
     uid: Option<u32>,
+
+    // Connect over TLS when set
+    tls: Option<Arc<ClientConfig>>,
+    colors: Colors,
+
+    // Shown below the inputs when logging in fails
+    status: Option<String>,
 }
 
 impl Login {
-    pub fn new() -> Self {
+    pub fn new(config: &Config) -> Self {
         Self {
-            address_input: String::new(),
-            username_input: String::new(),
-            character_index: 0,
+            address_input: config.server.clone(),
+            username_input: config.username.clone(),
+            password_input: config.password.clone(),
+            character_index: config.server.chars().count(),
             input_select: 0,
+            server: None,
             reader: None,
             writer: None,
             uid: None,
+            tls: config.tls.clone(),
+            colors: config.colors,
+            status: config.error.clone(),
         }
     }
 
     fn clamp_cursor(&self, pos: usize) -> usize {
-        let length = match self.input_select {
-            0 => self.address_input.chars().count(),
-            1 => self.username_input.chars().count(),
-            _ => 0,
-        };
-        pos.clamp(0, length)
+        pos.clamp(0, self.input().chars().count())
     }
 
     fn move_cursor_left(&mut self) {
@@ -58,12 +97,7 @@ impl Login {
 
     // Get the current input size in bytes
     fn byte_index(&self) -> usize {
-        let string = match self.input_select {
-            0 => self.address_input.clone(),
-            1 => self.username_input.clone(),
-            _ => String::new(),
-        };
-
+        let string = self.input();
         string
             .char_indices()
             .map(|(i, _)| i)
@@ -73,108 +107,126 @@ impl Login {
 
     fn enter_char(&mut self, c: char) {
         let index = self.byte_index();
-        match self.input_select {
-            0 => self.address_input.insert(index, c),
-            1 => self.username_input.insert(index, c),
-            _ => ()
-        }
+        self.input_mut().insert(index, c);
         self.move_cursor_right();
     }
 
     fn delete_char(&mut self) {
         let is_not_cursor_leftmost = self.character_index != 0;
         if is_not_cursor_leftmost {
-            match self.input_select {
-                0 => {
-                    let before_cursor = self.address_input.chars().take(self.character_index - 1);
-                    let after_cursor = self.address_input.chars().skip(self.character_index);
-
-                    self.address_input = before_cursor.chain(after_cursor).collect();
-                },
-                1 => {
-                    let before_cursor = self.username_input.chars().take(self.character_index - 1);
-                    let after_cursor = self.username_input.chars().skip(self.character_index);
-
-                    self.username_input = before_cursor.chain(after_cursor).collect();
-                },
-                _ => (),
-            }
-            
+            let index = self.character_index;
+            let input = self.input_mut();
+            let before_cursor = input.chars().take(index - 1);
+            let after_cursor = input.chars().skip(index);
+
+            *input = before_cursor.chain(after_cursor).collect();
             self.move_cursor_left();
         }
     }
 
     fn switch_inputs(&mut self) {
-        self.input_select = (self.input_select + 1) % 2;
-        self.character_index = self.byte_index();
+        self.input_select = (self.input_select + 1) % 3;
+        self.character_index = self.input().chars().count();
     }
 
-    fn submit_login(&mut self) {
-        // Connect to server
-        let stream = TcpStream::connect("127.0.0.1:8080")
-            .expect("Failed to connect to server");
+    // The input currently being edited
+    fn input(&self) -> &str {
+        match self.input_select {
+            0 => &self.address_input,
+            1 => &self.username_input,
+            _ => &self.password_input,
+        }
+    }
 
-        // Split TCP Stream
-        let stream_clone = stream.try_clone().unwrap();
-        let mut reader = BufReader::new(stream);
-        let mut writer = BufWriter::new(stream_clone);
+    fn input_mut(&mut self) -> &mut String {
+        match self.input_select {
+            0 => &mut self.address_input,
+            1 => &mut self.username_input,
+            _ => &mut self.password_input,
+        }
+    }
 
-        // Get UserID from server
-        let uid = loop {
-            let mut data = serde_json::Deserializer::from_reader(&mut reader);
-            let packet: Packet = Packet::deserialize(&mut data)
-                .expect("[ERROR] Failed to deserialize packet");
+    fn move_cursor_word_left(&mut self) {
+        self.character_index = input::word_left(self.input(), self.character_index);
+    }
 
-            if packet.packet_type == PacketType::IDAssign {
-                break packet.user_id
-            }
-            else {
-                println!("[ERROR] Unexpected packet type");
-            }
-        };
+    fn move_cursor_word_right(&mut self) {
+        self.character_index = input::word_right(self.input(), self.character_index);
+    }
 
-        // Send username to server
-        let username_packet = Packet {
-            packet_type: PacketType::UsernameChange,
-            user_id: uid,
-            contents: self.username_input.clone(),
+    fn clear_input(&mut self) {
+        self.input_mut().clear();
+        self.character_index = 0;
+    }
+
+    fn delete_word(&mut self) {
+        let index = self.character_index;
+        self.character_index = input::delete_word(self.input_mut(), index);
+    }
+
+    // Validate the inputs and connect, returning a message for the status
+    // line if anything goes wrong
+    fn login(&mut self) -> Result<(), String> {
+        self.username_input = validate_name(&self.username_input)?;
+        self.character_index = self.clamp_cursor(self.character_index);
+        self.submit_login()
+            .map_err(|error| format!("Failed to connect: {}", error))
+    }
+
+    fn submit_login(&mut self) -> io::Result<()> {
+        let server = Server {
+            address: server_address(&self.address_input),
+            tls: self.tls.clone(),
+            password: self.password_input.clone(),
         };
-        let json = serde_json::to_string(&username_packet)
-            .expect("[ERROR] Failed to serialize packet.");
-        writer.write(json.as_bytes()).expect("[ERROR] Failed to write username");
-        writer.flush().expect("[ERROR] Failed to send username.");
-   
+        let (uid, reader, writer) = server.connect(&self.username_input)?;
+
         self.uid = Some(uid);
+        self.server = Some(server);
         self.reader = Some(reader);
         self.writer = Some(writer);
+
+        Ok(())
     }
 
-    pub fn get_results(self) -> (u32, String, BufReader<TcpStream>, BufWriter<TcpStream>) {
-        (self.uid.unwrap(), self.username_input, self.reader.unwrap(), self.writer.unwrap())
+    pub fn get_results(self) -> (Server, u32, String, Reader, Writer) {
+        (
+            self.server.unwrap(),
+            self.uid.unwrap(),
+            self.username_input,
+            self.reader.unwrap(),
+            self.writer.unwrap(),
+        )
     }
 
     pub fn run(&mut self, terminal: &mut DefaultTerminal) -> io::Result<()> {
         loop {
             terminal.draw(|frame| self.draw(frame))?;
 
-            if event::poll(Duration::from_millis(16))? {
-                if let Event::Key(key) = event::read()? {
-                    match key.code {
-                        KeyCode::Esc => {
-                            ratatui::restore();
-                            process::exit(0);
-                        },
-                        KeyCode::Enter => {
-                            self.submit_login();
-                            break;
-                        }
-                        KeyCode::Tab => self.switch_inputs(),
-                        KeyCode::Char(to_insert) => self.enter_char(to_insert),
-                        KeyCode::Backspace => self.delete_char(),
-                        KeyCode::Left => self.move_cursor_left(),
-                        KeyCode::Right => self.move_cursor_right(),
-                        _ => (),
-                    }
+            if event::poll(Duration::from_millis(16))? &&
+                let Event::Key(key) = event::read()?
+            {
+                match key.code {
+                    KeyCode::Esc => {
+                        ui::restore_terminal();
+                        process::exit(0);
+                    },
+                    KeyCode::Enter => match self.login() {
+                        Ok(()) => break,
+                        Err(status) => self.status = Some(status),
+                    },
+                    KeyCode::Tab => self.switch_inputs(),
+                    KeyCode::Char('u') if key.modifiers.contains(KeyModifiers::CONTROL) => self.clear_input(),
+                    KeyCode::Char('w') if key.modifiers.contains(KeyModifiers::CONTROL) => self.delete_word(),
+                    KeyCode::Char(to_insert) => self.enter_char(to_insert),
+                    KeyCode::Backspace => self.delete_char(),
+                    KeyCode::Left if key.modifiers.contains(KeyModifiers::CONTROL) => self.move_cursor_word_left(),
+                    KeyCode::Right if key.modifiers.contains(KeyModifiers::CONTROL) => self.move_cursor_word_right(),
+                    KeyCode::Left => self.move_cursor_left(),
+                    KeyCode::Right => self.move_cursor_right(),
+                    KeyCode::Home => self.character_index = 0,
+                    KeyCode::End => self.character_index = self.input().chars().count(),
+                    _ => (),
                 }
             }
         }
@@ -183,6 +235,10 @@ impl Login {
     }
 
     fn draw(&self, frame: &mut Frame) {
+        if ui::too_small(frame, MIN_WIDTH, MIN_HEIGHT) {
+            return;
+        }
+
         let horizontal = Layout::horizontal([
             Constraint::Percentage(20),
             Constraint::Percentage(60),
@@ -195,38 +251,66 @@ impl Login {
         let input_prompts = Layout::vertical([
             Constraint::Length(3),
             Constraint::Length(3),
+            Constraint::Length(3),
+            Constraint::Length(1),
         ]);
 
         let [_, middle, _] = horizontal.areas(frame.area());
         let [_, center] = vertical.areas(middle);
-        let [server_input_area, username_input_area] = input_prompts.areas(center);
+        let [server_input_area, username_input_area, password_input_area, status_area] = input_prompts.areas(center);
 
         // Server Address input
         let server_input = Paragraph::new(self.address_input.as_str())
             .style(Style::default())
-            .block(Block::bordered().title("Server"));
+            .block(Block::bordered().title("Server").border_style(self.colors.border));
         frame.render_widget(server_input, server_input_area);
 
         // Username input
         let name_input = Paragraph::new(self.username_input.as_str())
             .style(Style::default())
-            .block(Block::bordered().title("Username"));
+            .block(Block::bordered().title("Username").border_style(self.colors.border));
         frame.render_widget(name_input, username_input_area);
-    
-        match self.input_select {
-            0 => {
-                frame.set_cursor_position((
-                    server_input_area.x + self.character_index as u16 + 1,
-                    server_input_area.y + 1,
-                ));
-            },
-            1 => {
-                frame.set_cursor_position((
-                    username_input_area.x + self.character_index as u16 + 1,
-                    username_input_area.y + 1,
-                ));
-            }
-            _ => (),
+
+        // Password input, masked
+        let password = "*".repeat(self.password_input.chars().count());
+        let password_input = Paragraph::new(password)
+            .style(Style::default())
+            .block(Block::bordered().title("Password (optional)").border_style(self.colors.border));
+        frame.render_widget(password_input, password_input_area);
+
+        // Status line
+        if let Some(status) = &self.status {
+            frame.render_widget(Paragraph::new(status.as_str()).fg(self.colors.error), status_area);
         }
+
+        let selected_area = match self.input_select {
+            0 => server_input_area,
+            1 => username_input_area,
+            _ => password_input_area,
+        };
+        ui::set_cursor(frame, selected_area.x + self.character_index as u16 + 1, selected_area.y + 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn addresses_get_the_default_port() {
+        assert_eq!(server_address(""), "127.0.0.1:8080");
+        assert_eq!(server_address("localhost"), "localhost:8080");
+        assert_eq!(server_address("chat.example.com"), "chat.example.com:8080");
+        assert_eq!(server_address("10.0.0.2"), "10.0.0.2:8080");
+        assert_eq!(server_address("::1"), "[::1]:8080");
+        assert_eq!(server_address("[::1]"), "[::1]:8080");
+    }
+
+    #[test]
+    fn addresses_with_a_port_are_kept() {
+        assert_eq!(server_address("localhost:9000"), "localhost:9000");
+        assert_eq!(server_address("[::1]:8080"), "[::1]:8080");
+        assert_eq!(server_address("[fe80::1]:9000"), "[fe80::1]:9000");
+        assert_eq!(server_address("rustchat://[::1]:9000/"), "[::1]:9000");
     }
 }
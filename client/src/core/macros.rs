@@ -0,0 +1,57 @@
+use std::collections::HashMap;
+
+// Shipped with the client. Macros from the config file are added to these,
+// replacing any with the same name.
+const BUILT_IN: &[(&str, &str)] = &[
+    ("lenny", "( ͡° ͜ʖ ͡°)"),
+    ("shrug", "¯\\_(ツ)_/¯"),
+    ("tableflip", "(╯°□°)╯︵ ┻━┻"),
+    ("unflip", "┬─┬ノ( º _ ºノ)"),
+];
+
+// Macro name (without the slash) -> text it stands for
+pub fn table(custom: &HashMap<String, String>) -> HashMap<String, String> {
+    let mut macros: HashMap<String, String> = BUILT_IN
+        .iter()
+        .map(|(name, text)| (name.to_string(), text.to_string()))
+        .collect();
+    for (name, text) in custom {
+        let name = name.trim().trim_start_matches('/');
+        if !name.is_empty() && !name.contains(char::is_whitespace) {
+            macros.insert(name.to_string(), text.clone());
+        }
+    }
+    macros
+}
+
+// Replace every word of the form `/name` that names a macro, keeping the
+// spacing around it
+pub fn expand(text: &str, macros: &HashMap<String, String>) -> String {
+    let mut result = String::with_capacity(text.len());
+    for piece in text.split_inclusive(char::is_whitespace) {
+        let word = piece.trim_end();
+        match word.strip_prefix('/').and_then(|name| macros.get(name)) {
+            Some(replacement) => {
+                result.push_str(replacement);
+                result.push_str(&piece[word.len()..]);
+            },
+            None => result.push_str(piece),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expands_whole_words_only() {
+        let custom = HashMap::from([(String::from("/brb"), String::from("be right back"))]);
+        let macros = table(&custom);
+        assert_eq!(expand("/shrug", &macros), "¯\\_(ツ)_/¯");
+        assert_eq!(expand("/brb, lunch", &macros), "/brb, lunch");
+        assert_eq!(expand("/brb  lunch\n/shrug", &macros), "be right back  lunch\n¯\\_(ツ)_/¯");
+        assert_eq!(expand("see /usr/shrug or a/shrug", &macros), "see /usr/shrug or a/shrug");
+    }
+}
@@ -0,0 +1,114 @@
+use ratatui::style::{Modifier, Style};
+use ratatui::text::Span;
+
+// Markers and what they do to the text between them. Code is shown as it
+// is, without looking for markers inside it.
+const MARKERS: &[(char, Modifier)] = &[
+    ('*', Modifier::BOLD),
+    ('_', Modifier::ITALIC),
+    ('`', Modifier::REVERSED),
+];
+
+// Split message text into spans, styling `*bold*`, `_italic_` and
+// `` `code` `` and leaving the markers out. Markers that aren't closed are
+// shown as they are.
+pub fn spans(text: &str, style: Style) -> Vec<Span<'static>> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut spans = Vec::new();
+    parse(&chars, style, &mut spans);
+    spans
+}
+
+fn parse(chars: &[char], style: Style, spans: &mut Vec<Span<'static>>) {
+    let mut literal = String::new();
+    let mut index = 0;
+    while index < chars.len() {
+        let marker = MARKERS.iter().find(|(marker, _)| *marker == chars[index]);
+        let Some((marker, modifier)) = marker.filter(|_| opens(chars, index)) else {
+            literal.push(chars[index]);
+            index += 1;
+            continue;
+        };
+        let Some(close) = (index + 2..chars.len()).find(|close| chars[*close] == *marker && closes(chars, *close)) else {
+            literal.push(chars[index]);
+            index += 1;
+            continue;
+        };
+
+        if !literal.is_empty() {
+            spans.push(Span::styled(std::mem::take(&mut literal), style));
+        }
+        let inner = &chars[index + 1..close];
+        if *marker == '`' {
+            spans.push(Span::styled(inner.iter().collect::<String>(), style.add_modifier(*modifier)));
+        }
+        else {
+            parse(inner, style.add_modifier(*modifier), spans);
+        }
+        index = close + 1;
+    }
+    if !literal.is_empty() {
+        spans.push(Span::styled(literal, style));
+    }
+}
+
+// Markers only count at the edges of words, so snake_case, 2*3*4 and
+// ¯\_(ツ)_/¯ are left alone
+fn opens(chars: &[char], index: usize) -> bool {
+    let before = index.checked_sub(1).map(|before| chars[before]);
+    let after = chars.get(index + 1);
+    !before.is_some_and(|before| before.is_alphanumeric() || before == '\\') &&
+        after.is_some_and(|after| !after.is_whitespace())
+}
+
+fn closes(chars: &[char], index: usize) -> bool {
+    let after = chars.get(index + 1);
+    !chars[index - 1].is_whitespace() && !after.is_some_and(|after| after.is_alphanumeric())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Each span's text and modifiers
+    fn parts(text: &str) -> Vec<(String, Modifier)> {
+        spans(text, Style::new())
+            .into_iter()
+            .map(|span| (span.content.to_string(), span.style.add_modifier))
+            .collect()
+    }
+
+    fn part(text: &str, modifier: Modifier) -> (String, Modifier) {
+        (text.to_string(), modifier)
+    }
+
+    #[test]
+    fn markers_style_the_text_between_them() {
+        assert_eq!(parts("a *bold* word"), [
+            part("a ", Modifier::empty()),
+            part("bold", Modifier::BOLD),
+            part(" word", Modifier::empty()),
+        ]);
+        assert_eq!(parts("`*not bold*`"), [part("*not bold*", Modifier::REVERSED)]);
+    }
+
+    #[test]
+    fn nested_and_adjacent_markers() {
+        assert_eq!(parts("*bold _both_*"), [
+            part("bold ", Modifier::BOLD),
+            part("both", Modifier::BOLD | Modifier::ITALIC),
+        ]);
+        assert_eq!(parts("*a*_b_`c`"), [
+            part("a", Modifier::BOLD),
+            part("b", Modifier::ITALIC),
+            part("c", Modifier::REVERSED),
+        ]);
+    }
+
+    #[test]
+    fn unclosed_and_mid_word_markers_are_literal() {
+        for text in ["*unclosed", "snake_case_name", "2*3*4", "** __", "a * b * c", "¯\\_(ツ)_/¯"] {
+            assert_eq!(parts(text), [part(text, Modifier::empty())]);
+        }
+    }
+}
@@ -1,3 +1,14 @@
 pub mod ui;
 pub mod login;
 pub mod net;
+pub mod config;
+pub mod tls;
+pub mod wrap;
+pub mod input;
+pub mod transfer;
+pub mod emoji;
+pub mod notify;
+pub mod headless;
+pub mod macros;
+pub mod markdown;
+pub mod save;
@@ -1,80 +1,1175 @@
 use std::sync::{Arc, Mutex};
-use std::collections::{HashMap};
-use std::io::{BufReader};
-use std::net::{TcpStream};
-use serde::{Serialize, Deserialize};
+use std::collections::{HashMap, HashSet};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::PathBuf;
+use chrono::{Local, TimeZone};
+use ratatui::style::Color;
+use rustls::ClientConfig;
 
-#[derive(Default, Serialize, Deserialize)]
-pub struct Message {
-    pub uid: u32,
-    pub sender_id: u32,
-    pub message: String,
+use crate::core::config::Notifications;
+use crate::core::notify;
+use crate::core::tls;
+use crate::core::transfer::{self, Incoming, Transfer};
+
+// Halves of the connection to the server, either plain TCP or TLS
+pub type Reader = BufReader<Box<dyn Read + Send>>;
+pub type Writer = PacketWriter<BufWriter<Box<dyn Write + Send>>>;
+
+// The UI and listener threads both send packets, so the connection's
+// write half is shared between them
+pub type SharedWriter = Arc<Mutex<Writer>>;
+
+// How long to wait for the server to start the handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Reconnection attempts back off from the first delay up to the maximum
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+// Longest username the server accepts, in characters
+const MAX_NAME_LENGTH: usize = 32;
+
+// How long a /ping waits for the server to answer
+const PING_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Users who haven't sent a message for this long are shown as idle
+const IDLE_THRESHOLD: Duration = Duration::from_secs(5 * 60);
+
+pub use common::{
+    FEATURE_DEFLATE, FEATURE_MSGPACK, FileInfo, HISTORY_PAGE, Message, PROTOCOL_VERSION, Packet, PacketType,
+    PacketWriter, RECEIPT_WINDOW,
+};
+
+// Every packet is sent as a 4-byte big-endian length prefix followed by
+// that many bytes of JSON or MessagePack, deflated if the prefix says so.
+pub fn write_packet(writer: &mut PacketWriter<impl Write>, packet: &Packet) -> io::Result<()> {
+    let (prefix, data) = writer.encode(packet)?;
+    let inner = writer.get_mut();
+    inner.write_all(&prefix.to_be_bytes())?;
+    inner.write_all(&data)?;
+    inner.flush()
+}
+
+// The whole frame is read before it is parsed, however the bytes arrive.
+// Returns an `UnexpectedEof` error once the server has closed the connection
+pub fn read_packet(reader: &mut impl Read) -> io::Result<Packet> {
+    let (prefix, data) = read_frame(reader)?;
+    common::decode(prefix, &data)
+}
+
+// A frame's length prefix and the bytes after it, still to be decoded
+fn read_frame(reader: &mut impl Read) -> io::Result<(u32, Vec<u8>)> {
+    let mut prefix = [0; 4];
+    reader.read_exact(&mut prefix)?;
+    let prefix = u32::from_be_bytes(prefix);
+    let mut data = vec![0; common::frame_length(prefix)?];
+    reader.read_exact(&mut data)?;
+    Ok((prefix, data))
+}
+
+// Check a username before sending it, returning the trimmed name or the
+// reason it would be rejected. Mirrors the server's validation.
+pub fn validate_name(name: &str) -> Result<String, &'static str> {
+    let name = name.trim();
+    if name.is_empty() {
+        Err("Username must not be empty")
+    }
+    else if name.chars().any(char::is_control) {
+        Err("Username must not contain control characters")
+    }
+    else if name.chars().count() > MAX_NAME_LENGTH {
+        Err("Username must be at most 32 characters")
+    }
+    else {
+        Ok(name.to_string())
+    }
+}
+
+// Format a unix timestamp as `HH:MM` in the local timezone
+pub fn format_time(timestamp: u64) -> String {
+    match Local.timestamp_opt(timestamp as i64, 0).single() {
+        Some(time) => time.format("%H:%M").to_string(),
+        None => String::from("--:--"),
+    }
+}
+
+// Format a unix timestamp as `YYYY-MM-DD HH:MM` in the local timezone,
+// for messages that may be from another day
+pub fn format_date(timestamp: u64) -> String {
+    match Local.timestamp_opt(timestamp as i64, 0).single() {
+        Some(time) => time.format("%Y-%m-%d %H:%M").to_string(),
+        None => String::from("---------- --:--"),
+    }
 }
 
-#[derive(Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub enum PacketType {
+// A duration as its largest whole unit, e.g. `5m` or `2h`
+pub fn format_idle(idle: Duration) -> String {
+    let minutes = idle.as_secs() / 60;
+    match minutes {
+        0..60 => format!("{}m", minutes),
+        60..1440 => format!("{}h", minutes / 60),
+        _ => format!("{}d", minutes / 1440),
+    }
+}
+
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+// How far one of our own messages got. Messages from others and from
+// history are always `Received`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    Received,
+    // Shown straight away, waiting for the server to store it
+    Pending { nonce: u32, since: Instant },
+    // The server stored it
+    Confirmed,
+    // The server didn't confirm it in time or turned it down
+    Failed,
+}
+
+// One line of the message pane
+#[derive(Clone)]
+pub enum ChatEntry {
+    Message {
+        uid: u32,
+        sender_id: u32,
+        sender_name: String,
+        timestamp: u64,
+        text: String,
+        edited: bool,
+        action: bool,
+        reply_to: Option<u32>,
+        delivery: Delivery,
+    },
+    Whisper {
+        sender_id: u32,
+        target_id: u32,
+        sender_name: String,
+        timestamp: u64,
+        text: String,
+    },
+    // Joins, leaves, renames and status changes. Can be hidden.
+    System(String),
+    // Errors, output of local commands and other lines that are always shown
+    Notice(String),
+    // The server's message of the day, sent when connecting
+    Motd(String),
+    // A file being sent or received, updated as it progresses
+    Transfer(Transfer),
+}
+
+impl ChatEntry {
+    pub fn from_message(message: Message) -> Self {
+        ChatEntry::Message {
+            uid: message.uid,
+            sender_id: message.sender_id,
+            sender_name: message.sender_name,
+            timestamp: message.timestamp,
+            text: message.message,
+            edited: message.edited,
+            action: message.action,
+            reply_to: message.reply_to,
+            delivery: Delivery::Received,
+        }
+    }
+}
+
+// How things stand with the server
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ConnectionState {
     #[default]
-    None,
-    IDAssign,
-    UserConnected,
-    UserDisconnected,
-    UserList,
-    UsernameChange,
-    NewMessage,
-}
-
-#[derive(Default, Clone, Serialize, Deserialize)]
-pub struct Packet {
-    pub packet_type: PacketType, 
-    
-    pub user_id: u32,
-    pub contents: String,
-} 
+    Connected,
+    // Retrying, with the number of the attempt in progress
+    Reconnecting(u32),
+    // Lost, and either not retried yet or never to be
+    Disconnected,
+}
 
 #[derive(Default)]
 pub struct ClientState {
+    pub uid: u32,
     pub username: String,
     pub users: HashMap<u32, String>,
-    pub messages: Vec<String>,
+    pub messages: Vec<ChatEntry>,
+
+    // Room the server last confirmed we are in
+    pub room: String,
+
+    // Away users and the reason they gave
+    pub away: HashMap<u32, String>,
+
+    // Users composing a message and when we last heard they were typing
+    pub typing: HashMap<u32, Instant>,
+
+    // Updated as the connection is lost and reconnection is attempted
+    pub connection_state: ConnectionState,
+
+    // Set when the server announces it is shutting down
+    pub shutdown: bool,
+
+    // Set when the connection was closed on purpose, by us or the server,
+    // so there is no point reconnecting
+    pub closed: bool,
+
+    // Where received files are saved
+    pub download_dir: PathBuf,
+
+    // Files being received, by sender and transfer id
+    pub incoming: HashMap<(u32, u32), Incoming>,
+
+    // Desktop notifications are only shown while unfocused, unless the
+    // settings say otherwise
+    pub notifications: Notifications,
+    pub focused: bool,
+
+    // Who has seen each recent message, by message id, and the newest
+    // message we have said we've seen
+    pub receipts: HashMap<u32, Vec<u32>>,
+    pub receipt_sent: u32,
+
+    // Set while older history has been asked for, and once the server has
+    // none left to send
+    pub history_pending: bool,
+    pub history_complete: bool,
+
+    // Entries ever inserted above the rest by older history, so the
+    // message pane can keep its place
+    pub prepended: usize,
+
+    // When each user last sent a message, as far as we know. The server
+    // only says so every so often.
+    pub last_active: HashMap<u32, Instant>,
+
+    // Name colors users picked with /color. Everyone else gets one
+    // picked from their id.
+    pub name_colors: HashMap<u32, Color>,
+
+    // Users hidden with /mute. Only this client knows about it.
+    pub muted: HashSet<u32>,
+
+    // Most entries kept, with the oldest dropped past it. Unlimited when
+    // unset.
+    pub max_messages: Option<usize>,
+
+    // Entries ever dropped from the top to stay under the cap, so the
+    // message pane can keep its place
+    pub dropped: usize,
+
+    // A /ping waiting to be answered, with the timestamp it carried and
+    // when it was sent
+    pub ping: Option<(String, Instant)>,
+}
+
+impl ClientState {
+    // Everyone we know of, alphabetically ignoring case. Ties are broken by
+    // id so the order never depends on how the map happens to iterate.
+    pub fn sorted_users(&self) -> Vec<(u32, &str)> {
+        let mut users: Vec<(u32, &str)> = self.users
+            .iter()
+            .map(|(uid, name)| (*uid, name.as_str()))
+            .collect();
+        users.sort_by(|a, b| a.1.to_lowercase().cmp(&b.1.to_lowercase()).then(a.0.cmp(&b.0)));
+        users
+    }
+
+    // Id of the oldest stored message we have, to ask for the ones before it
+    pub fn oldest_message(&self) -> Option<u32> {
+        self.messages.iter().find_map(|entry| match entry {
+            ChatEntry::Message { uid, .. } if *uid != 0 => Some(*uid),
+            _ => None,
+        })
+    }
+
+    // Start timing a /ping, returning the packet to send. Keepalives have
+    // no contents, so the timestamp tells the server's echo apart.
+    pub fn start_ping(&mut self) -> Packet {
+        let stamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis())
+            .to_string();
+        self.ping = Some((stamp.clone(), Instant::now()));
+        Packet {
+            packet_type: PacketType::Ping,
+            user_id: self.uid,
+            contents: stamp,
+            ..Default::default()
+        }
+    }
+
+    // Give up on a /ping the server never answered
+    pub fn expire_ping(&mut self) {
+        if self.ping.as_ref().is_some_and(|(_, sent)| sent.elapsed() > PING_TIMEOUT) {
+            self.ping = None;
+            self.messages.push(ChatEntry::Notice(format!(
+                "Ping timed out after {}s",
+                PING_TIMEOUT.as_secs(),
+            )));
+        }
+    }
+
+    // How long a user has gone without sending anything, once that's long
+    // enough to count as idle
+    pub fn idle_for(&self, uid: u32) -> Option<Duration> {
+        let idle = self.last_active.get(&uid)?.elapsed();
+        (uid != self.uid && idle >= IDLE_THRESHOLD).then_some(idle)
+    }
+
+    // Drop the oldest entries past the cap. The server still has the
+    // messages among them.
+    pub fn trim_messages(&mut self) {
+        if let Some(max) = self.max_messages && self.messages.len() > max {
+            let excess = self.messages.len() - max;
+            self.messages.drain(..excess);
+            self.dropped += excess;
+            self.history_complete = false;
+        }
+    }
+
+    // Whether an entry is a message or whisper from a muted user
+    pub fn is_muted(&self, entry: &ChatEntry) -> bool {
+        match entry {
+            ChatEntry::Message { sender_id, .. } | ChatEntry::Whisper { sender_id, .. } => {
+                self.muted.contains(sender_id)
+            },
+            _ => false,
+        }
+    }
+
+    pub fn mark_failed(&mut self, failed: u32) {
+        for entry in self.messages.iter_mut() {
+            if let ChatEntry::Message { delivery, .. } = entry &&
+                matches!(delivery, Delivery::Pending { nonce, .. } if *nonce == failed)
+            {
+                *delivery = Delivery::Failed;
+            }
+        }
+    }
 }
 
-pub fn server_listen(mut stream: BufReader<TcpStream>, state: Arc<Mutex<ClientState>>) {
+// Where and how to connect, kept so a lost connection can be re-established
+#[derive(Clone)]
+pub struct Server {
+    pub address: String,
+    pub tls: Option<Arc<ClientConfig>>,
+
+    // Sent if the server asks for one
+    pub password: String,
+}
+
+impl Server {
+    // Connect and log in as `username`, returning the id the server
+    // assigned along with both halves of the connection
+    pub fn connect(&self, username: &str) -> io::Result<(u32, Reader, Writer)> {
+        // Hostnames can resolve to several addresses, each tried in turn
+        let addresses: Vec<SocketAddr> = self.address.to_socket_addrs()
+            .map_err(|error| io::Error::new(error.kind(), format!("Couldn't resolve {}: {}", self.address, error)))?
+            .collect();
+        let stream = TcpStream::connect(addresses.as_slice())?;
+
+        // A TLS server never sends a plaintext client anything, so don't
+        // wait forever for the handshake
+        stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+
+        // Split stream
+        let (read, write): (Box<dyn Read + Send>, Box<dyn Write + Send>) = match &self.tls {
+            Some(config) => {
+                let (read, write) = tls::connect(config.clone(), server_host(&self.address), stream.try_clone()?)?;
+                (Box::new(read), Box::new(write))
+            },
+            None => (Box::new(stream.try_clone()?), Box::new(stream.try_clone()?)),
+        };
+        let mut reader = BufReader::new(read);
+        let mut writer = PacketWriter::new(BufWriter::new(write));
+
+        // Get UserID from server
+        let (uid, features) = loop {
+            let packet = read_packet(&mut reader).map_err(|error| match error.kind() {
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut if self.tls.is_none() => io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "No response from server, it may require TLS (--tls)",
+                ),
+                _ => error,
+            })?;
+
+            match packet.packet_type {
+                PacketType::IDAssign if packet.version != PROTOCOL_VERSION => return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "Server uses protocol version {} but this client uses version {}",
+                        packet.version,
+                        PROTOCOL_VERSION,
+                    ),
+                )),
+                PacketType::IDAssign => break (packet.user_id, packet.features),
+                PacketType::ServerFull => return Err(io::Error::new(
+                    io::ErrorKind::ConnectionRefused,
+                    packet.contents,
+                )),
+                // Sent even when empty, since the password may be optional
+                PacketType::Auth => {
+                    let auth_packet = Packet {
+                        packet_type: PacketType::Auth,
+                        contents: self.password.clone(),
+                        ..Default::default()
+                    };
+                    write_packet(&mut writer, &auth_packet)?;
+                },
+                PacketType::Error => return Err(io::Error::other(packet.contents)),
+                _ => (),
+            }
+        };
+
+        // Send username to server, accepting compression and MessagePack if
+        // they were offered. Servers that don't offer them wouldn't
+        // understand the frames.
+        let accepted: Vec<String> = features
+            .into_iter()
+            .filter(|feature| feature == FEATURE_DEFLATE || feature == FEATURE_MSGPACK)
+            .collect();
+        let deflate = accepted.iter().any(|feature| feature == FEATURE_DEFLATE);
+        let msgpack = accepted.iter().any(|feature| feature == FEATURE_MSGPACK);
+        let username_packet = Packet {
+            packet_type: PacketType::UsernameChange,
+            user_id: uid,
+            contents: username.to_string(),
+            features: accepted,
+            version: PROTOCOL_VERSION,
+            ..Default::default()
+        };
+        write_packet(&mut writer, &username_packet)?;
+        writer.compress = deflate;
+        writer.msgpack = msgpack;
+        stream.set_read_timeout(None)?;
+
+        Ok((uid, reader, writer))
+    }
+}
+
+// The host part of a `host:port` pair, without IPv6 brackets
+fn server_host(address: &str) -> &str {
+    address
+        .rsplit_once(':')
+        .map_or(address, |(host, _)| host)
+        .trim_matches(['[', ']'])
+}
+
+// Handle packets from the server, reconnecting whenever the connection
+// is lost. Only returns once the server shuts down.
+pub fn server_listen(
+    server: Server,
+    mut stream: Reader,
+    writer: SharedWriter,
+    state: Arc<Mutex<ClientState>>,
+) {
     loop {
-        let mut data = serde_json::Deserializer::from_reader(&mut stream);
-        let packet = Packet::deserialize(&mut data)
-            .expect("[ERROR] Failed to deserialize packet");
+        listen(&mut stream, &writer, &state);
+        {
+            let s = state.lock().unwrap();
+            if s.shutdown || s.closed {
+                return;
+            }
+        }
+
+        let mut delay = RECONNECT_DELAY;
+        let mut attempt = 1;
+        loop {
+            state.lock().unwrap().connection_state = ConnectionState::Reconnecting(attempt);
+            thread::sleep(delay);
+
+            let username = state.lock().unwrap().username.clone();
+            if let Ok((uid, reader, new_writer)) = server.connect(&username) {
+                stream = reader;
+                *writer.lock().unwrap() = new_writer;
+
+                // Start over with a fresh view of the server, then go
+                // back to the room we were in
+                let room = {
+                    let mut s = state.lock().unwrap();
+                    s.uid = uid;
+                    s.users.clear();
+                    s.users.insert(uid, username);
+                    s.away.clear();
+                    s.typing.clear();
+                    s.connection_state = ConnectionState::Connected;
+                    s.room.clone()
+                };
+                if !room.is_empty() {
+                    let join_packet = Packet {
+                        packet_type: PacketType::JoinRoom,
+                        user_id: uid,
+                        room,
+                        ..Default::default()
+                    };
+                    let _ = write_packet(&mut *writer.lock().unwrap(), &join_packet);
+                }
+                break;
+            }
+
+            delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+            attempt += 1;
+        }
+    }
+}
+
+// Handle packets until the connection is lost or the server shuts down
+fn listen(stream: &mut Reader, writer: &SharedWriter, state: &Mutex<ClientState>) {
+    loop {
+        // The server closed the connection, or a frame was too broken to
+        // know where the next one starts
+        let (prefix, data) = match read_frame(stream) {
+            Ok(frame) => frame,
+            Err(error) => {
+                let mut s = state.lock().unwrap();
+                s.connection_state = ConnectionState::Disconnected;
+                transfer::abort_incoming(&mut s, None, "Connection lost");
+                if error.kind() != io::ErrorKind::UnexpectedEof {
+                    s.messages.push(ChatEntry::Notice(format!("Error: Connection lost: {}", error)));
+                }
+                break;
+            },
+        };
+
+        // The frame was read whole, so the next one still lines up
+        let packet = match common::decode(prefix, &data) {
+            Ok(packet) => packet,
+            Err(error) => {
+                let text = format!("Error: Ignored a malformed packet from the server: {}", error);
+                state.lock().unwrap().messages.push(ChatEntry::Notice(text));
+                continue;
+            },
+        };
+
+        // Answer keepalives without involving the UI
+        if packet.packet_type == PacketType::Ping {
+            let pong = Packet {
+                packet_type: PacketType::Pong,
+                ..Default::default()
+            };
+            let _ = write_packet(&mut *writer.lock().unwrap(), &pong);
+            continue;
+        }
 
         let mut s = state.lock().unwrap();
 
+        // Older history is asked for by scrolling up, so it is kept even
+        // past the cap until newer entries push it out
+        let older = packet.packet_type == PacketType::HistoryRequest;
+
         match packet.packet_type {
             PacketType::UserConnected => {
                 s.users.insert(packet.user_id, packet.contents.clone());
-                s.messages.push(format!("{} joined the chat", packet.contents));
+                s.last_active.insert(packet.user_id, Instant::now());
+                s.messages.push(ChatEntry::System(format!("{} joined the chat", packet.contents)));
             },
             PacketType::UserDisconnected => {
-                let user = s.users.get(&packet.user_id)
-                    .expect("[ERROR] User doesn't exist")
-                    .clone();
-                s.messages.push(format!("{} left the chat", user));
-                s.users.remove(&packet.user_id).expect("[ERROR] Failed to remove user");
+                transfer::abort_incoming(&mut s, Some(packet.user_id), "Sender left");
+                s.typing.remove(&packet.user_id);
+                s.away.remove(&packet.user_id);
+                s.last_active.remove(&packet.user_id);
+                s.name_colors.remove(&packet.user_id);
+                if let Some(user) = s.users.remove(&packet.user_id) {
+                    let text = if packet.contents.is_empty() {
+                        format!("{} left the chat", user)
+                    }
+                    else {
+                        format!("{} left the chat ({})", user, packet.contents)
+                    };
+                    s.messages.push(ChatEntry::System(text));
+                }
             },
             PacketType::UserList => {
                 s.users.insert(packet.user_id, packet.contents.clone());
+                let since = Instant::now().checked_sub(Duration::from_secs(packet.idle)).unwrap_or_else(Instant::now);
+                s.last_active.insert(packet.user_id, since);
             }
+            PacketType::UserActive => {
+                s.last_active.insert(packet.user_id, Instant::now());
+            },
             PacketType::UsernameChange => {
-                let user = s.users.get_mut(&packet.user_id)
-                    .expect("[ERROR] User does not exist");
-                let old_name = user.clone();
-                *user = packet.contents.clone();
-                s.messages.push(format!("{} changed their name to {}", old_name, packet.contents.clone()));
+                // The server may have adjusted our name to keep it unique
+                if packet.user_id == s.uid {
+                    s.username = packet.contents.clone();
+                }
+
+                // If we never saw this user join, just start tracking them
+                if let Some(old_name) = s.users.insert(packet.user_id, packet.contents.clone()) &&
+                    old_name != packet.contents
+                {
+                    s.messages.push(ChatEntry::System(format!(
+                        "{} changed their name to {}",
+                        old_name,
+                        packet.contents,
+                    )));
+                }
             },
             PacketType::NewMessage => {
-                let username = s.users.get(&packet.user_id)
-                    .expect("[ERROR] User does not exist")
-                    .clone();
-                s.messages.push(format!("({}) {}", username, packet.contents.trim()));
+                let message = packet.message.unwrap_or_default();
+
+                // The sender may not be known yet if packets arrived out of
+                // order, so fall back to the name stored with the message
+                let username = match s.users.get(&packet.user_id) {
+                    Some(name) => name.clone(),
+                    None if !message.sender_name.is_empty() => message.sender_name.clone(),
+                    None => packet.user_id.to_string(),
+                };
+                s.typing.remove(&packet.user_id);
+
+                // Our own messages are already shown once acknowledged
+                let shown = s.messages.iter().any(|entry| {
+                    matches!(entry, ChatEntry::Message { uid, .. } if *uid == message.uid)
+                });
+                if shown {
+                    continue;
+                }
+
+                if packet.user_id != s.uid && !s.muted.contains(&packet.user_id) &&
+                    notify::mentions(&packet.contents, &s.username)
+                {
+                    notify::notify(&s, format!("{} mentioned you", username), packet.contents.trim().to_string());
+                }
+
+                s.messages.push(ChatEntry::Message {
+                    uid: message.uid,
+                    sender_id: packet.user_id,
+                    sender_name: username,
+                    timestamp: message.timestamp,
+                    text: packet.contents.trim().to_string(),
+                    edited: message.edited,
+                    action: message.action,
+                    reply_to: message.reply_to,
+                    delivery: Delivery::Received,
+                });
+            },
+            PacketType::Ack => {
+                let Some(message) = packet.message else {
+                    continue;
+                };
+                for entry in s.messages.iter_mut() {
+                    if let ChatEntry::Message { uid, timestamp, text, delivery, .. } = entry &&
+                        matches!(delivery, Delivery::Pending { nonce, .. } if *nonce == packet.nonce)
+                    {
+                        // The server may have changed the text, e.g. expanding shortcodes
+                        *uid = message.uid;
+                        *timestamp = message.timestamp;
+                        *text = message.message.clone();
+                        *delivery = Delivery::Confirmed;
+                    }
+                }
+            },
+            PacketType::PrivateMessage => {
+                let Some(message) = packet.message else {
+                    continue;
+                };
+                if message.sender_id != s.uid && !s.muted.contains(&message.sender_id) {
+                    notify::notify(&s, format!("Message from {}", message.sender_name), message.message.clone());
+                }
+                s.messages.push(ChatEntry::Whisper {
+                    sender_id: message.sender_id,
+                    target_id: packet.target,
+                    sender_name: message.sender_name,
+                    timestamp: message.timestamp,
+                    text: message.message,
+                });
+            },
+            PacketType::EditMessage => {
+                let Some(message) = packet.message else {
+                    continue;
+                };
+                for entry in s.messages.iter_mut() {
+                    if let ChatEntry::Message { uid, text, edited, .. } = entry &&
+                        *uid == message.uid
+                    {
+                        *text = message.message.clone();
+                        *edited = true;
+                    }
+                }
+            },
+            PacketType::DeleteMessage => {
+                let Some(message) = packet.message else {
+                    continue;
+                };
+                for entry in s.messages.iter_mut() {
+                    if let ChatEntry::Message { uid, .. } = entry &&
+                        *uid == message.uid
+                    {
+                        *entry = ChatEntry::Notice(String::from("(message deleted)"));
+                    }
+                }
+            },
+            PacketType::StatusChange => {
+                let name = s.users.get(&packet.user_id).cloned().unwrap_or_else(|| packet.user_id.to_string());
+                // An empty reason means the user is back
+                if packet.contents.is_empty() {
+                    if s.away.remove(&packet.user_id).is_some() {
+                        s.messages.push(ChatEntry::System(format!("{} is back", name)));
+                    }
+                }
+                else {
+                    s.messages.push(ChatEntry::System(format!("{} is away: {}", name, packet.contents)));
+                    s.away.insert(packet.user_id, packet.contents);
+                }
+            },
+            // The server has checked the color, so one that doesn't parse
+            // here just leaves the default
+            PacketType::ColorChange => {
+                match packet.contents.parse() {
+                    Ok(color) => s.name_colors.insert(packet.user_id, color),
+                    Err(_) => s.name_colors.remove(&packet.user_id),
+                };
+                if packet.user_id == s.uid {
+                    let text = match packet.contents.as_str() {
+                        "" => String::from("Your name color is back to the default"),
+                        color => format!("Your name color is now {}", color),
+                    };
+                    s.messages.push(ChatEntry::Notice(text));
+                }
+            },
+            PacketType::Typing => {
+                s.typing.insert(packet.user_id, Instant::now());
+            },
+            PacketType::StoppedTyping => {
+                s.typing.remove(&packet.user_id);
+            },
+            PacketType::RateLimited => {
+                s.mark_failed(packet.nonce);
+                s.messages.push(ChatEntry::Notice(packet.contents));
+            },
+            PacketType::Error => {
+                s.messages.push(ChatEntry::Notice(format!("Error: {}", packet.contents)));
+            },
+            PacketType::Kick if packet.user_id == s.uid => {
+                s.messages.push(ChatEntry::Notice(packet.contents));
+                s.closed = true;
+            },
+            PacketType::Kick => {
+                // Forget them now so their disconnect isn't announced twice
+                transfer::abort_incoming(&mut s, Some(packet.user_id), "Sender left");
+                s.users.remove(&packet.user_id);
+                s.away.remove(&packet.user_id);
+                s.typing.remove(&packet.user_id);
+                s.messages.push(ChatEntry::System(packet.contents));
+            },
+            PacketType::FileStart | PacketType::FileChunk | PacketType::FileEnd => {
+                transfer::receive(&mut s, packet);
+            },
+            PacketType::IdleKick | PacketType::VersionMismatch => {
+                s.messages.push(ChatEntry::Notice(packet.contents));
+                s.closed = true;
+            },
+            PacketType::ServerShutdown => {
+                s.shutdown = true;
+                break;
+            },
+            PacketType::JoinRoom => {
+                // The server follows this with the room's history
+                s.room = packet.room;
+                // The message of the day stays at the top whatever the room
+                s.messages.retain(|entry| matches!(entry, ChatEntry::Motd(_)));
+                s.typing.clear();
+                s.receipts.clear();
+                s.receipt_sent = 0;
+                s.history_pending = false;
+                // Older history was dropped by the server
+                s.history_complete = !packet.contents.is_empty();
+                if !packet.contents.is_empty() {
+                    s.messages.push(ChatEntry::Notice(packet.contents));
+                }
+            },
+            // A page of older messages, to go above the ones we have. Pages
+            // for a room we have since left are dropped.
+            PacketType::HistoryRequest if packet.room == s.room => {
+                s.history_pending = false;
+                s.history_complete = packet.history.len() < packet.limit as usize;
+                let mut older: Vec<ChatEntry> = packet.history.into_iter().map(ChatEntry::from_message).collect();
+                if !packet.contents.is_empty() {
+                    older.insert(0, ChatEntry::Notice(packet.contents));
+                }
+                s.prepended += older.len();
+                let top = s.messages.iter().take_while(|entry| matches!(entry, ChatEntry::Motd(_))).count();
+                s.messages.splice(top..top, older);
+            },
+            // The server echoing a /ping
+            PacketType::Pong => {
+                if let Some((stamp, sent)) = &s.ping && *stamp == packet.contents {
+                    let text = format!("Pong: {}ms", sent.elapsed().as_millis());
+                    s.ping = None;
+                    s.messages.push(ChatEntry::Notice(text));
+                }
+            },
+            PacketType::Stats => {
+                let plural = if packet.count == 1 { "" } else { "s" };
+                let mut text = format!("{} has sent {} message{} this session", packet.contents, packet.count, plural);
+                if !packet.address.is_empty() {
+                    text.push_str(&format!(", connected from {}", packet.address));
+                }
+                s.messages.push(ChatEntry::Notice(text));
+            },
+            PacketType::WhoisRequest => {
+                let from = match packet.address.is_empty() {
+                    true => String::new(),
+                    false => format!(" from {}", packet.address),
+                };
+                let plural = if packet.count == 1 { "" } else { "s" };
+                let mut text = format!(
+                    "{} is user #{}, connected since {}{}, and has sent {} message{}",
+                    packet.contents,
+                    packet.target,
+                    format_date(packet.connected_at),
+                    from,
+                    packet.count,
+                    plural,
+                );
+                if !packet.previous_names.is_empty() {
+                    let names: Vec<&str> = packet.previous_names.iter().rev().map(String::as_str).collect();
+                    text.push_str(&format!(". Formerly known as {}", names.join(", ")));
+                }
+                s.messages.push(ChatEntry::Notice(text));
+            },
+            // A summary comes first, then the matches from each room
+            PacketType::Grep if packet.room.is_empty() => {
+                let text = match (packet.count, packet.limit) {
+                    (0, _) => format!("No messages match \"{}\"", packet.contents),
+                    (count, shown) if shown < count => format!(
+                        "{} messages match \"{}\", showing the newest {}:",
+                        count,
+                        packet.contents,
+                        shown,
+                    ),
+                    (1, _) => format!("1 message matches \"{}\":", packet.contents),
+                    (count, _) => format!("{} messages match \"{}\":", count, packet.contents),
+                };
+                s.messages.push(ChatEntry::Notice(text));
+            },
+            PacketType::Grep => {
+                s.messages.push(ChatEntry::Notice(format!("#{}", packet.room)));
+                for message in packet.history {
+                    s.messages.push(ChatEntry::Notice(format!(
+                        "  [{}] #{} {}: {}",
+                        format_date(message.timestamp),
+                        message.uid,
+                        message.sender_name,
+                        message.message,
+                    )));
+                }
+            },
+            // Sent again on every reconnect, but once is enough
+            PacketType::Motd => {
+                let shown = s.messages
+                    .iter()
+                    .any(|entry| matches!(entry, ChatEntry::Motd(motd) if *motd == packet.contents));
+                if !shown {
+                    s.messages.push(ChatEntry::Motd(packet.contents));
+                }
+            },
+            // Rolled by the server so nobody can fake the result
+            PacketType::DiceRoll => s.messages.push(ChatEntry::Notice(packet.contents)),
+            PacketType::ReadReceipt => {
+                if let Some(message) = packet.message {
+                    s.receipts.retain(|uid, _| message.uid.saturating_sub(*uid) < RECEIPT_WINDOW);
+                    s.receipts.insert(message.uid, packet.readers);
+                }
+            },
+            PacketType::MessageHistory => {
+                if let Some(message) = packet.message {
+                    s.messages.push(ChatEntry::from_message(message));
+                }
             },
             _ => () 
         }
+
+        if !older {
+            s.trim_messages();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use super::*;
+
+    // Hands out a few bytes per read, so frames and the characters in them
+    // arrive split at every possible point
+    struct Trickle {
+        data: Cursor<Vec<u8>>,
+        step: usize,
+    }
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.step = self.step % 7 + 1;
+            let len = buf.len().min(self.step);
+            self.data.read(&mut buf[..len])
+        }
+    }
+
+    // Run the listener over a recorded stream of packets until it ends
+    fn replay(packets: &[Packet]) -> ClientState {
+        replay_bytes(record(packets))
+    }
+
+    fn record(packets: &[Packet]) -> Vec<u8> {
+        let mut data = PacketWriter::new(Vec::new());
+        for packet in packets {
+            write_packet(&mut data, packet).unwrap();
+        }
+        data.into_inner()
+    }
+
+    fn replay_bytes(data: Vec<u8>) -> ClientState {
+        let read: Box<dyn Read + Send> = Box::new(Cursor::new(data));
+        let write: Box<dyn Write + Send> = Box::new(io::sink());
+        let mut reader = BufReader::new(read);
+        let writer = Arc::new(Mutex::new(PacketWriter::new(BufWriter::new(write))));
+        let state = Mutex::new(ClientState::default());
+
+        listen(&mut reader, &writer, &state);
+        state.into_inner().unwrap()
+    }
+
+    #[test]
+    fn packets_from_unknown_users_do_not_panic() {
+        let state = replay(&[
+            Packet {
+                packet_type: PacketType::NewMessage,
+                user_id: 7,
+                contents: String::from("hi"),
+                message: Some(Message {
+                    uid: 1,
+                    sender_id: 7,
+                    message: String::from("hi"),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+            Packet {
+                packet_type: PacketType::UserDisconnected,
+                user_id: 8,
+                ..Default::default()
+            },
+            Packet {
+                packet_type: PacketType::UsernameChange,
+                user_id: 9,
+                contents: String::from("carol"),
+                ..Default::default()
+            },
+            Packet {
+                packet_type: PacketType::StatusChange,
+                user_id: 10,
+                contents: String::from("lunch"),
+                ..Default::default()
+            },
+        ]);
+
+        assert_eq!(state.connection_state, ConnectionState::Disconnected);
+        assert_eq!(state.users.get(&9).map(String::as_str), Some("carol"));
+        assert!(matches!(
+            &state.messages[0],
+            ChatEntry::Message { sender_name, text, .. } if sender_name == "7" && text == "hi"
+        ));
+    }
+
+    #[test]
+    fn message_before_join_uses_stored_name() {
+        let state = replay(&[
+            Packet {
+                packet_type: PacketType::NewMessage,
+                user_id: 7,
+                contents: String::from("early"),
+                message: Some(Message {
+                    uid: 1,
+                    sender_id: 7,
+                    sender_name: String::from("alice"),
+                    message: String::from("early"),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+            Packet {
+                packet_type: PacketType::UserConnected,
+                user_id: 7,
+                contents: String::from("alice"),
+                ..Default::default()
+            },
+        ]);
+
+        assert!(matches!(
+            &state.messages[0],
+            ChatEntry::Message { sender_name, .. } if sender_name == "alice"
+        ));
+    }
+
+    #[test]
+    fn older_history_goes_above_newer() {
+        let message = |uid| Message {
+            uid,
+            sender_id: 7,
+            message: format!("message {}", uid),
+            ..Default::default()
+        };
+        let state = replay(&[
+            Packet {
+                packet_type: PacketType::JoinRoom,
+                room: String::from("general"),
+                ..Default::default()
+            },
+            Packet {
+                packet_type: PacketType::MessageHistory,
+                room: String::from("general"),
+                message: Some(message(5)),
+                ..Default::default()
+            },
+            Packet {
+                packet_type: PacketType::HistoryRequest,
+                room: String::from("general"),
+                contents: String::from("Earlier messages are no longer available"),
+                limit: HISTORY_PAGE,
+                history: vec![message(3), message(4)],
+                ..Default::default()
+            },
+        ]);
+
+        let uids: Vec<u32> = state.messages
+            .iter()
+            .filter_map(|entry| match entry {
+                ChatEntry::Message { uid, .. } => Some(*uid),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(uids, [3, 4, 5]);
+        assert!(matches!(&state.messages[0], ChatEntry::Notice(_)));
+        assert!(state.history_complete);
+        assert_eq!(state.prepended, 3);
+    }
+
+    #[test]
+    fn idle_time_comes_from_the_user_list() {
+        let listed = |uid| Packet {
+            packet_type: PacketType::UserList,
+            user_id: uid,
+            contents: uid.to_string(),
+            idle: 600,
+            ..Default::default()
+        };
+        let state = replay(&[
+            listed(7),
+            listed(8),
+            Packet {
+                packet_type: PacketType::UserActive,
+                user_id: 8,
+                ..Default::default()
+            },
+        ]);
+
+        assert!(state.idle_for(7).is_some_and(|idle| format_idle(idle) == "10m"));
+        assert_eq!(state.idle_for(8), None);
+    }
+
+    #[test]
+    fn malformed_packets_are_skipped_and_eof_disconnects() {
+        let notice = |text: &str| Packet {
+            packet_type: PacketType::DiceRoll,
+            contents: text.to_string(),
+            ..Default::default()
+        };
+        let mut data = record(&[notice("before")]);
+        data.extend(7u32.to_be_bytes());
+        data.extend(b"garbage");
+        data.extend(record(&[notice("after")]));
+        // Closed partway through the last frame
+        let cut = record(&[notice("never")]);
+        data.extend(&cut[..cut.len() / 2]);
+
+        let state = replay_bytes(data);
+        let notices: Vec<&str> = state.messages
+            .iter()
+            .filter_map(|entry| match entry {
+                ChatEntry::Notice(text) => Some(text.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(notices.len(), 3);
+        assert_eq!(notices[0], "before");
+        assert!(notices[1].starts_with("Error: Ignored a malformed packet"));
+        assert_eq!(notices[2], "after");
+        assert_eq!(state.connection_state, ConnectionState::Disconnected);
+    }
+
+    #[test]
+    fn multibyte_text_split_across_reads_is_intact() {
+        // Padding shifts where the characters fall relative to the reads
+        let packets: Vec<Packet> = (0..8)
+            .map(|pad| Packet {
+                packet_type: PacketType::NewMessage,
+                contents: format!("{}😄日本語🎉 ¯\\_(ツ)_/¯ 한국어", "x".repeat(pad)),
+                ..Default::default()
+            })
+            .collect();
+        let mut data = PacketWriter::new(Vec::new());
+        for packet in &packets {
+            write_packet(&mut data, packet).unwrap();
+        }
+
+        let trickle = Trickle { data: Cursor::new(data.into_inner()), step: 0 };
+        let mut reader = BufReader::with_capacity(5, trickle);
+        for packet in &packets {
+            assert_eq!(&read_packet(&mut reader).unwrap(), packet);
+        }
+        assert_eq!(read_packet(&mut reader).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn motd_is_kept_once_across_joins() {
+        let motd = |text: &str| Packet {
+            packet_type: PacketType::Motd,
+            contents: text.to_string(),
+            ..Default::default()
+        };
+        // As if the connection dropped and the server greeted us again
+        let join = Packet {
+            packet_type: PacketType::JoinRoom,
+            room: String::from("general"),
+            ..Default::default()
+        };
+        let welcome = motd("Welcome!\nBe nice");
+        let state = replay(&[welcome.clone(), join.clone(), welcome, join, motd("Rules changed")]);
+
+        let motds: Vec<&str> = state.messages.iter()
+            .filter_map(|entry| match entry {
+                ChatEntry::Motd(text) => Some(text.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(motds, ["Welcome!\nBe nice", "Rules changed"]);
+    }
+
+    #[test]
+    fn whois_lists_former_names_newest_first() {
+        let whois = |previous_names: &[&str]| Packet {
+            packet_type: PacketType::WhoisRequest,
+            contents: String::from("carol"),
+            target: 7,
+            count: 1,
+            previous_names: previous_names.iter().map(|name| name.to_string()).collect(),
+            ..Default::default()
+        };
+        let state = replay(&[whois(&[]), whois(&["alice", "bob"])]);
+
+        let notices: Vec<&str> = state.messages.iter()
+            .filter_map(|entry| match entry {
+                ChatEntry::Notice(text) => Some(text.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(notices.len(), 2);
+        assert!(notices[0].ends_with("has sent 1 message"));
+        assert!(notices[1].ends_with("has sent 1 message. Formerly known as bob, alice"));
     }
 }
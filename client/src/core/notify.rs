@@ -0,0 +1,59 @@
+use std::thread;
+use notify_rust::Notification;
+
+use crate::core::config::Notifications;
+use crate::core::net::ClientState;
+
+// Whether `name` appears in `text` as a word of its own, ignoring case
+pub fn mentions(text: &str, name: &str) -> bool {
+    let text: Vec<char> = text.to_lowercase().chars().collect();
+    let name: Vec<char> = name.to_lowercase().chars().collect();
+    if name.is_empty() || name.len() > text.len() {
+        return false;
+    }
+
+    let is_word = |c: &char| c.is_alphanumeric() || *c == '_';
+    (0..=text.len() - name.len()).any(|start| {
+        let end = start + name.len();
+        text[start..end] == name[..] &&
+            (start == 0 || !is_word(&text[start - 1])) &&
+            text.get(end).is_none_or(|c| !is_word(c))
+    })
+}
+
+// Show a desktop notification if the settings allow it right now. Sending
+// one can block on the notification daemon, so it is done on its own thread.
+pub fn notify(s: &ClientState, summary: String, body: String) {
+    let wanted = match s.notifications {
+        Notifications::Always => true,
+        Notifications::Unfocused => !s.focused,
+        Notifications::Off => false,
+    };
+    if wanted {
+        thread::spawn(move || {
+            // Nothing useful to do without a notification daemon
+            let _ = Notification::new()
+                .appname("rust-chat")
+                .summary(&summary)
+                .body(&body)
+                .show();
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mentions_match_whole_words_ignoring_case() {
+        assert!(mentions("hey Alice, you there?", "alice"));
+        assert!(mentions("ALICE", "alice"));
+        assert!(mentions("thanks @alice!", "alice"));
+        assert!(mentions("ask Mary Ann", "mary ann"));
+        assert!(!mentions("alicea said hi", "alice"));
+        assert!(!mentions("malice", "alice"));
+        assert!(!mentions("alice_b", "alice"));
+        assert!(!mentions("hello", ""));
+    }
+}
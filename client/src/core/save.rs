@@ -0,0 +1,103 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use chrono::Local;
+
+use crate::core::net::{ChatEntry, ClientState, Delivery, format_date};
+
+// Where /save writes when no path is given: the download directory, in a
+// file named after the time of the save
+pub fn default_path(s: &ClientState) -> PathBuf {
+    s.download_dir.join(format!("rustchat-{}.log", Local::now().format("%Y%m%d-%H%M%S")))
+}
+
+// Add the message pane to the end of a text file, under a line saying
+// which room it came from and when, so saving again to the same file
+// keeps the earlier saves. Returns how many entries were written.
+pub fn save(path: &Path, s: &ClientState) -> io::Result<usize> {
+    if let Some(dir) = path.parent() && !dir.as_os_str().is_empty() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let mut text = format!("--- #{} saved {} ---\n", s.room, Local::now().format("%Y-%m-%d %H:%M:%S"));
+    for entry in &s.messages {
+        text.push_str(&entry_line(entry, s));
+        text.push('\n');
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(text.as_bytes())?;
+    Ok(s.messages.len())
+}
+
+fn entry_line(entry: &ChatEntry, s: &ClientState) -> String {
+    match entry {
+        ChatEntry::Message { uid, sender_id, sender_name, timestamp, text, edited, action, delivery, .. } => {
+            let name = s.users.get(sender_id).unwrap_or(sender_name);
+            let mut line = if *action {
+                format!("[{}] #{} * {} {}", format_date(*timestamp), uid, name, text)
+            }
+            else {
+                format!("[{}] #{} {}: {}", format_date(*timestamp), uid, name, text)
+            };
+            if *edited {
+                line.push_str(" (edited)");
+            }
+            if matches!(delivery, Delivery::Failed) {
+                line.push_str(" (not sent)");
+            }
+            line
+        },
+        ChatEntry::Whisper { sender_id, target_id, sender_name, timestamp, text } => {
+            if *sender_id == s.uid {
+                let target = s.users.get(target_id).cloned().unwrap_or_else(|| target_id.to_string());
+                format!("[{}] [DM to {}] {}", format_date(*timestamp), target, text)
+            }
+            else {
+                format!("[{}] [DM from {}] {}", format_date(*timestamp), sender_name, text)
+            }
+        },
+        ChatEntry::System(text) => format!("— {}", text),
+        ChatEntry::Notice(text) | ChatEntry::Motd(text) => text.clone(),
+        ChatEntry::Transfer(transfer) => transfer.summary(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saving_twice_appends() {
+        let mut s = ClientState {
+            uid: 1,
+            room: String::from("general"),
+            ..Default::default()
+        };
+        s.users.insert(2, String::from("bob"));
+        s.messages.push(ChatEntry::System(String::from("bob joined the chat")));
+        s.messages.push(ChatEntry::Message {
+            uid: 7,
+            sender_id: 2,
+            sender_name: String::from("bob"),
+            timestamp: 0,
+            text: String::from("hello"),
+            edited: true,
+            action: false,
+            reply_to: None,
+            delivery: Delivery::Received,
+        });
+
+        let path = std::env::temp_dir().join(format!("rustchat-save-test-{}.log", std::process::id()));
+        let _ = fs::remove_file(&path);
+        assert_eq!(save(&path, &s).unwrap(), 2);
+        assert_eq!(save(&path, &s).unwrap(), 2);
+        let text = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 6);
+        assert!(lines[0].starts_with("--- #general saved ") && lines[3].starts_with("--- #general saved "));
+        assert_eq!(lines[1], "— bob joined the chat");
+        assert!(lines[2].ends_with("] #7 bob: hello (edited)"));
+    }
+}
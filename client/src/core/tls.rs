@@ -0,0 +1,111 @@
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use rustls::{ClientConfig, ClientConnection, pki_types::ServerName};
+
+// The listener thread reads while the UI thread writes, but a TLS session
+// can't be split like a TcpStream. Both halves share the session and each
+// gets its own handle to the socket. The lock is never held while waiting
+// on the socket, so a blocked read doesn't hold up writes.
+type Session = Arc<Mutex<ClientConnection>>;
+
+pub struct TlsReader {
+    session: Session,
+    socket: TcpStream,
+
+    // Received bytes not yet handed to the session
+    incoming: Vec<u8>,
+}
+
+pub struct TlsWriter {
+    session: Session,
+    socket: TcpStream,
+}
+
+// Perform the handshake and split the connection into its two halves.
+// `host` is the name the server's certificate must be valid for.
+pub fn connect(
+    config: Arc<ClientConfig>,
+    host: &str,
+    mut socket: TcpStream,
+) -> io::Result<(TlsReader, TlsWriter)> {
+    let server_name = ServerName::try_from(host.to_string())
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))?;
+    let mut session = ClientConnection::new(config, server_name)
+        .map_err(io::Error::other)?;
+
+    while session.is_handshaking() {
+        session.complete_io(&mut socket)
+            .map_err(|error| match error.kind() {
+                io::ErrorKind::InvalidData => io::Error::new(
+                    error.kind(),
+                    format!("TLS handshake failed ({}), is the server using TLS?", error),
+                ),
+                _ => error,
+            })?;
+    }
+
+    let session = Arc::new(Mutex::new(session));
+    let reader = TlsReader {
+        session: session.clone(),
+        socket: socket.try_clone()?,
+        incoming: Vec::new(),
+    };
+    let writer = TlsWriter { session, socket };
+    Ok((reader, writer))
+}
+
+// Send whatever the session has queued up for the server
+fn flush_session(session: &mut ClientConnection, socket: &mut TcpStream) -> io::Result<()> {
+    while session.wants_write() {
+        session.write_tls(socket)?;
+    }
+    Ok(())
+}
+
+impl Read for TlsReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            {
+                let mut session = self.session.lock().unwrap();
+                match session.reader().read(buf) {
+                    Err(error) if error.kind() == io::ErrorKind::WouldBlock => (),
+                    result => return result,
+                }
+
+                // Decrypt what has already arrived before waiting for more
+                if !self.incoming.is_empty() {
+                    let used = session.read_tls(&mut self.incoming.as_slice())?;
+                    self.incoming.drain(..used);
+                    session.process_new_packets()
+                        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+                    flush_session(&mut session, &mut self.socket)?;
+                    continue;
+                }
+            }
+
+            let mut chunk = [0; 16 * 1024];
+            let length = self.socket.read(&mut chunk)?;
+            if length == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            self.incoming.extend_from_slice(&chunk[..length]);
+        }
+    }
+}
+
+impl Write for TlsWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut session = self.session.lock().unwrap();
+        let length = session.writer().write(buf)?;
+        flush_session(&mut session, &mut self.socket)?;
+        Ok(length)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut session = self.session.lock().unwrap();
+        session.writer().flush()?;
+        flush_session(&mut session, &mut self.socket)?;
+        self.socket.flush()
+    }
+}
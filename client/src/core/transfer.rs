@@ -0,0 +1,395 @@
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+
+use crate::core::net::{
+    ChatEntry, ClientState, ConnectionState, FileInfo, Packet, PacketType, SharedWriter, write_packet,
+};
+
+// Largest file that can be sent, matching the server's limit
+pub const MAX_FILE_SIZE: u64 = 10 << 20;
+
+// Bytes of the file carried by each chunk, before base64 encoding
+const CHUNK_SIZE: usize = 48 << 10;
+
+#[derive(Clone, PartialEq, Eq)]
+pub enum TransferStatus {
+    Active,
+    Done,
+    Failed(String),
+}
+
+// A file being sent or received, shown in the message pane with its progress
+#[derive(Clone)]
+pub struct Transfer {
+    pub transfer_id: u32,
+    pub sender_id: u32,
+    pub outgoing: bool,
+
+    // Name of the other user
+    pub peer: String,
+
+    pub name: String,
+    pub size: u64,
+    pub done: u64,
+
+    // Where a received file is saved
+    pub path: Option<PathBuf>,
+
+    pub status: TransferStatus,
+}
+
+impl Transfer {
+    pub fn summary(&self) -> String {
+        let (verb, direction) = if self.outgoing { ("Sending", "to") } else { ("Receiving", "from") };
+        match &self.status {
+            TransferStatus::Active => format!(
+                "{} {} {} {}… {}% ({} of {})",
+                verb,
+                self.name,
+                direction,
+                self.peer,
+                (self.done * 100).checked_div(self.size).unwrap_or(100),
+                format_size(self.done),
+                format_size(self.size),
+            ),
+            TransferStatus::Done if self.outgoing => {
+                format!("Sent {} to {} ({})", self.name, self.peer, format_size(self.size))
+            },
+            TransferStatus::Done => format!(
+                "Received {} from {} ({}), saved to {}",
+                self.name,
+                self.peer,
+                format_size(self.size),
+                self.path.as_deref().unwrap_or(Path::new("")).display(),
+            ),
+            TransferStatus::Failed(reason) => {
+                format!("{} {} {} {} failed: {}", verb, self.name, direction, self.peer, reason)
+            },
+        }
+    }
+}
+
+// A file we are writing to the download directory
+pub struct Incoming {
+    file: BufWriter<File>,
+    path: PathBuf,
+    size: u64,
+    received: u64,
+    next_sequence: u32,
+}
+
+// e.g. "512 B", "48.0 KB", "1.2 MB"
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["KB", "MB", "GB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+// Apply a change to a transfer's entry in the message pane, if it is still there
+fn update(s: &mut ClientState, sender_id: u32, transfer_id: u32, change: impl FnOnce(&mut Transfer)) {
+    let entry = s.messages.iter_mut().rev().find_map(|entry| match entry {
+        ChatEntry::Transfer(transfer) if transfer.sender_id == sender_id && transfer.transfer_id == transfer_id => {
+            Some(transfer)
+        },
+        _ => None,
+    });
+    if let Some(transfer) = entry {
+        change(transfer);
+    }
+}
+
+// Announce the file to `target` and stream it from a background thread,
+// so the UI stays responsive. Errors before anything is sent are returned.
+pub fn send_file(writer: SharedWriter, state: Arc<Mutex<ClientState>>, target: u32, path: &Path) -> io::Result<()> {
+    let file = File::open(path)?;
+    let metadata = file.metadata()?;
+    if !metadata.is_file() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Not a file"));
+    }
+    let size = metadata.len();
+    if size > MAX_FILE_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Files can be at most {}", format_size(MAX_FILE_SIZE)),
+        ));
+    }
+    let name = path.file_name().map_or_else(|| String::from("file"), |name| name.to_string_lossy().into_owned());
+
+    let transfer_id = rand::random();
+    let sender_id = {
+        let mut s = state.lock().unwrap();
+        let transfer = Transfer {
+            transfer_id,
+            sender_id: s.uid,
+            outgoing: true,
+            peer: s.users.get(&target).cloned().unwrap_or_else(|| target.to_string()),
+            name: name.clone(),
+            size,
+            done: 0,
+            path: None,
+            status: TransferStatus::Active,
+        };
+        s.messages.push(ChatEntry::Transfer(transfer));
+        s.uid
+    };
+
+    let start = Packet {
+        packet_type: PacketType::FileStart,
+        user_id: sender_id,
+        target,
+        file: Some(FileInfo {
+            transfer_id,
+            name,
+            size,
+            sequence: 0,
+        }),
+        ..Default::default()
+    };
+    if let Err(error) = write_packet(&mut *writer.lock().unwrap(), &start) {
+        update(&mut state.lock().unwrap(), sender_id, transfer_id, |transfer| {
+            transfer.status = TransferStatus::Failed(error.to_string());
+        });
+        return Err(error);
+    }
+
+    thread::spawn(move || {
+        let result = stream_chunks(&writer, &state, file.take(size), sender_id, target, transfer_id);
+        let mut s = state.lock().unwrap();
+        update(&mut s, sender_id, transfer_id, |transfer| {
+            transfer.status = match result {
+                Ok(()) => TransferStatus::Done,
+                Err(error) => TransferStatus::Failed(error.to_string()),
+            };
+        });
+    });
+    Ok(())
+}
+
+fn stream_chunks(
+    writer: &SharedWriter,
+    state: &Mutex<ClientState>,
+    mut file: impl Read,
+    sender_id: u32,
+    target: u32,
+    transfer_id: u32,
+) -> io::Result<()> {
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut sequence = 0;
+    loop {
+        let read = file.read(&mut buffer)?;
+
+        // Chunks sent after a reconnect would come from a different user
+        {
+            let s = state.lock().unwrap();
+            if s.connection_state != ConnectionState::Connected || s.closed || s.uid != sender_id {
+                return Err(io::Error::new(io::ErrorKind::NotConnected, "Connection lost"));
+            }
+            if !s.users.contains_key(&target) {
+                return Err(io::Error::other("Recipient left"));
+            }
+        }
+
+        let packet = Packet {
+            packet_type: if read == 0 { PacketType::FileEnd } else { PacketType::FileChunk },
+            user_id: sender_id,
+            contents: BASE64.encode(&buffer[..read]),
+            target,
+            file: Some(FileInfo {
+                transfer_id,
+                sequence,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        write_packet(&mut *writer.lock().unwrap(), &packet)?;
+        if read == 0 {
+            return Ok(());
+        }
+
+        sequence += 1;
+        update(&mut state.lock().unwrap(), sender_id, transfer_id, |transfer| transfer.done += read as u64);
+    }
+}
+
+// Handle a FileStart, FileChunk or FileEnd from another user
+pub fn receive(s: &mut ClientState, packet: Packet) {
+    let Some(file) = packet.file else {
+        return;
+    };
+    let key = (packet.user_id, file.transfer_id);
+
+    let result = match packet.packet_type {
+        PacketType::FileStart => {
+            let name = file_name(&file.name);
+            let transfer = Transfer {
+                transfer_id: file.transfer_id,
+                sender_id: packet.user_id,
+                outgoing: false,
+                peer: s.users.get(&packet.user_id).cloned().unwrap_or_else(|| packet.user_id.to_string()),
+                name: name.clone(),
+                size: file.size,
+                done: 0,
+                path: None,
+                status: TransferStatus::Active,
+            };
+            s.messages.push(ChatEntry::Transfer(transfer));
+
+            if file.size > MAX_FILE_SIZE {
+                Err(format!("Files can be at most {}", format_size(MAX_FILE_SIZE)))
+            }
+            else {
+                match create_download(&s.download_dir, &name) {
+                    Ok((path, handle)) => {
+                        update(s, key.0, key.1, |transfer| transfer.path = Some(path.clone()));
+                        s.incoming.insert(key, Incoming {
+                            file: BufWriter::new(handle),
+                            path,
+                            size: file.size,
+                            received: 0,
+                            next_sequence: 0,
+                        });
+                        Ok(())
+                    },
+                    Err(error) => Err(error.to_string()),
+                }
+            }
+        },
+        PacketType::FileChunk => {
+            // Chunks of a transfer that already failed are dropped
+            let Some(incoming) = s.incoming.get_mut(&key) else {
+                return;
+            };
+            let received = write_chunk(incoming, file.sequence, &packet.contents);
+            received.map(|received| update(s, key.0, key.1, |transfer| transfer.done = received))
+        },
+        PacketType::FileEnd => {
+            let Some(incoming) = s.incoming.get_mut(&key) else {
+                return;
+            };
+            if file.sequence != incoming.next_sequence || incoming.received != incoming.size {
+                Err(String::from("The file arrived incomplete"))
+            }
+            else if let Err(error) = incoming.file.flush() {
+                Err(error.to_string())
+            }
+            else {
+                s.incoming.remove(&key);
+                update(s, key.0, key.1, |transfer| transfer.status = TransferStatus::Done);
+                Ok(())
+            }
+        },
+        _ => Ok(()),
+    };
+
+    if let Err(reason) = result {
+        fail(s, key, reason);
+    }
+}
+
+// Check a chunk follows the previous one and append it to the file,
+// returning the number of bytes received so far
+fn write_chunk(incoming: &mut Incoming, sequence: u32, contents: &str) -> Result<u64, String> {
+    if sequence != incoming.next_sequence {
+        return Err(String::from("Parts of the file arrived out of order"));
+    }
+    let data = BASE64.decode(contents).map_err(|_| String::from("Received corrupt data"))?;
+    if incoming.received + data.len() as u64 > incoming.size {
+        return Err(String::from("Received more data than announced"));
+    }
+    incoming.file.write_all(&data).map_err(|error| error.to_string())?;
+    incoming.received += data.len() as u64;
+    incoming.next_sequence += 1;
+    Ok(incoming.received)
+}
+
+// Stop receiving a file and remove what was written of it
+fn fail(s: &mut ClientState, key: (u32, u32), reason: String) {
+    if let Some(incoming) = s.incoming.remove(&key) {
+        drop(incoming.file);
+        let _ = fs::remove_file(&incoming.path);
+    }
+    update(s, key.0, key.1, |transfer| {
+        transfer.path = None;
+        transfer.status = TransferStatus::Failed(reason);
+    });
+}
+
+// Give up on files being received from `sender`, or from everyone when
+// the connection is lost
+pub fn abort_incoming(s: &mut ClientState, sender: Option<u32>, reason: &str) {
+    let keys: Vec<(u32, u32)> = s.incoming
+        .keys()
+        .filter(|(sender_id, _)| sender.is_none_or(|sender| sender == *sender_id))
+        .copied()
+        .collect();
+    for key in keys {
+        fail(s, key, reason.to_string());
+    }
+}
+
+// The sender picks the name, so only its last component is used to keep
+// the file inside the download directory
+fn file_name(name: &str) -> String {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let name: String = name.chars().filter(|c| !c.is_control()).collect();
+    match name.trim() {
+        "" | "." | ".." => String::from("file"),
+        name => name.to_string(),
+    }
+}
+
+// Create a new file in the download directory, adding a number to the
+// name if one by that name already exists
+fn create_download(dir: &Path, name: &str) -> io::Result<(PathBuf, File)> {
+    fs::create_dir_all(dir)?;
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, format!(".{}", extension)),
+        _ => (name, String::new()),
+    };
+
+    let mut copy = 0;
+    loop {
+        let candidate = match copy {
+            0 => dir.join(name),
+            _ => dir.join(format!("{} ({}){}", stem, copy, extension)),
+        };
+        match File::create_new(&candidate) {
+            Ok(file) => return Ok((candidate, file)),
+            Err(error) if error.kind() == io::ErrorKind::AlreadyExists => copy += 1,
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_names_stay_in_download_dir() {
+        assert_eq!(file_name("photo.png"), "photo.png");
+        assert_eq!(file_name("../../.bashrc"), ".bashrc");
+        assert_eq!(file_name("C:\\Users\\bob\\notes.txt"), "notes.txt");
+        assert_eq!(file_name("dir/.."), "file");
+        assert_eq!(file_name(""), "file");
+    }
+
+    #[test]
+    fn sizes_are_readable() {
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(48 << 10), "48.0 KB");
+        assert_eq!(format_size(MAX_FILE_SIZE), "10.0 MB");
+    }
+}
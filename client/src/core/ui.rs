@@ -1,76 +1,878 @@
+use std::collections::HashMap;
 use std::process;
 use std::thread;
-use std::time::Duration;
-use std::io::{self, BufWriter, Write};
-use std::net::{TcpStream};
+use std::time::{Duration, Instant};
+use std::io;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
+use rustls::ClientConfig;
+use unicode_width::UnicodeWidthStr;
 use ratatui::{
-    crossterm::event::{self, Event, KeyCode},
-    layout::{Constraint, Layout,},
-    style::{Style, Stylize},
-    text::{Line,},
-    widgets::{Block, List, Paragraph, ListItem},
+    crossterm::{
+        event::{
+            self, DisableFocusChange, DisableMouseCapture, EnableFocusChange, EnableMouseCapture, Event,
+            KeyCode, KeyModifiers,
+            MouseButton, MouseEvent, MouseEventKind,
+        },
+        execute,
+        terminal::SetTitle,
+    },
+    layout::{Constraint, Flex, Layout, Position, Rect},
+    style::{Color, Style, Stylize},
+    text::{Line, Span},
+    widgets::{Block, Clear, List, ListItem, ListState, Paragraph, Tabs, Wrap},
     DefaultTerminal, Frame,
 };
 
-use crate::core::login::Login;
-use crate::core::net::{self, ClientState, Packet, PacketType,};
+use crate::core::config::{Colors, Config, Notifications, Theme};
+use crate::core::emoji;
+use crate::core::input;
+use crate::core::login::{self, Login};
+use crate::core::macros;
+use crate::core::markdown;
+use crate::core::save;
+use crate::core::transfer;
+use crate::core::wrap;
+use crate::core::net::{
+    self, ChatEntry, ClientState, ConnectionState, Delivery, HISTORY_PAGE, Message, Packet, PacketType, Reader,
+    Server, SharedWriter, Writer, format_time, unix_time, write_packet,
+};
+
+// Mouse capture and focus reporting aren't part of ratatui's setup, so
+// they are switched on and off alongside it
+pub fn init_terminal() -> io::Result<DefaultTerminal> {
+    let terminal = ratatui::init();
+    execute!(io::stdout(), EnableMouseCapture, EnableFocusChange)?;
+    Ok(terminal)
+}
+
+pub fn restore_terminal() {
+    let _ = execute!(io::stdout(), DisableMouseCapture, DisableFocusChange);
+    ratatui::restore();
+}
+
+// When the frame is smaller than a screen's layout needs, draw a notice
+// in its place and return true so the screen skips drawing itself
+pub fn too_small(frame: &mut Frame, width: u16, height: u16) -> bool {
+    let area = frame.area();
+    if area.width >= width && area.height >= height {
+        return false;
+    }
+    let notice = format!("Terminal too small (need at least {}x{})", width, height);
+    // Narrow terminals get the notice over a few lines
+    let [middle] = Layout::vertical([Constraint::Length(3)]).flex(Flex::Center).areas(area);
+    frame.render_widget(Paragraph::new(notice).centered().wrap(Wrap { trim: true }), middle);
+    true
+}
 
-pub struct App {}
+// Keeps the cursor inside the frame when the text before it is wider
+// than the box it's typed in
+pub fn set_cursor(frame: &mut Frame, x: u16, y: u16) {
+    let area = frame.area();
+    frame.set_cursor_position((
+        x.min(area.right().saturating_sub(1)),
+        y.min(area.bottom().saturating_sub(1)),
+    ));
+}
+
+pub struct App {
+    config: Config,
+}
 
 impl App {
-    pub fn new() -> Self {
-        Self {}
+    pub fn new(config: Config) -> Self {
+        Self { config }
     }
 
     pub fn run(&self, mut terminal: DefaultTerminal) -> io::Result<()> {
         // Create and run login 
-        let mut login = Login::new();
+        let mut login = Login::new(&self.config);
         {
             let _ = login.run(&mut terminal);
         }
-        let (uid, username, reader, writer) = login.get_results();
+        let (server, uid, username, reader, writer) = login.get_results();
 
-        // Create shared state
-        let state = Arc::new(Mutex::new(ClientState::default()));
+        // Create and run chat
+        let settings = TabSettings {
+            download_dir: self.config.download_dir.clone(),
+            notifications: self.config.notifications,
+            max_messages: self.config.max_messages,
+        };
+        let tab = Tab::start(server, uid, username, reader, writer, &settings);
+        let chat = Chat::new(
+            tab,
+            self.config.colors,
+            self.config.right_align_own,
+            self.config.tls.clone(),
+            settings,
+            macros::table(&self.config.macros),
+        );
+        chat.run(terminal)
+    }
+}
 
-        // Add self to state
+// Settings from the config file that every tab's connection starts with
+struct TabSettings {
+    download_dir: PathBuf,
+    notifications: Notifications,
+    max_messages: Option<usize>,
+}
+
+// One server connection and everything received over it
+struct Tab {
+    // Server address, shown as the tab's title
+    name: String,
+    stream: SharedWriter,
+    state: Arc<Mutex<ClientState>>,
+}
+
+impl Tab {
+    // Start listening on a connection that has just logged in
+    fn start(
+        server: Server,
+        uid: u32,
+        username: String,
+        reader: Reader,
+        writer: Writer,
+        settings: &TabSettings,
+    ) -> Self {
+        let state = Arc::new(Mutex::new(ClientState::default()));
         {
             let mut s = state.lock().unwrap();
             s.users.insert(uid, username.clone());
+            s.uid = uid;
             s.username = username;
+            s.download_dir = settings.download_dir.clone();
+            s.notifications = settings.notifications;
+            s.max_messages = settings.max_messages;
+            s.focused = true;
         }
 
-        // Create and run chat
-        let chat = Chat::new(writer, uid);
+        let name = server.address.clone();
+        let stream = Arc::new(Mutex::new(writer));
+        {
+            let stream = stream.clone();
+            let state = state.clone();
+            thread::spawn(move || net::server_listen(server, reader, stream, state));
+        }
+
+        Self { name, stream, state }
+    }
+
+    // Tell the server we are leaving and stop reconnecting
+    fn close(&self, message: &str) {
+        let mut s = self.state.lock().unwrap();
+        s.closed = true;
+        let packet = Packet {
+            packet_type: PacketType::UserDisconnected,
+            user_id: s.uid,
+            contents: message.to_string(),
+            ..Default::default()
+        };
+        let _ = write_packet(&mut *self.stream.lock().unwrap(), &packet);
+    }
+}
+
+// While typing we re-announce it this often, and stop announcing once the
+// input has been left alone for a while. Others drop the indicator if it
+// isn't refreshed in time, e.g. when the stop signal never arrives.
+const TYPING_RESEND: Duration = Duration::from_secs(3);
+const TYPING_IDLE: Duration = Duration::from_secs(5);
+const TYPING_TIMEOUT: Duration = Duration::from_secs(6);
 
-        // Create threads
-        let state_clone = state.clone();
-        let listen_thread = thread::spawn(move || net::server_listen(reader, state_clone));
-        let ui_thread = thread::spawn(move || chat.run(terminal, state));
-        
-        listen_thread.join().unwrap();
-        let _ = ui_thread.join().unwrap();
+// Our messages are marked as failed if the server doesn't confirm them in time
+const ACK_TIMEOUT: Duration = Duration::from_secs(10);
 
-        Ok(())
+// Read receipts are sent at most this often, however fast the view scrolls
+const RECEIPT_INTERVAL: Duration = Duration::from_secs(1);
+
+// Lines moved per step of the mouse wheel
+const MOUSE_SCROLL_LINES: usize = 3;
+
+// The input box grows with the lines typed up to this many, then scrolls
+const MAX_INPUT_LINES: usize = 5;
+
+// Submitted inputs remembered for Up/Down
+const INPUT_HISTORY_LEN: usize = 100;
+
+// Most names shown at once above the input while completing with Tab
+const MAX_COMPLETIONS_SHOWN: usize = 8;
+
+// Characters of the parent message quoted above a reply
+const REPLY_SNIPPET_LEN: usize = 40;
+
+// Smallest terminal that fits the message pane, user list and input box
+const MIN_WIDTH: u16 = 40;
+const MIN_HEIGHT: u16 = 10;
+
+struct Command {
+    name: &'static str,
+    args: &'static str,
+    description: &'static str,
+}
+
+// Every command the chat input understands. The /help listing is built
+// from this table, so new commands must be added here.
+const COMMANDS: &[Command] = &[
+    Command {
+        name: "/away",
+        args: "[reason]",
+        description: "Mark yourself as away until you send a message or use /back",
+    },
+    Command {
+        name: "/back",
+        args: "",
+        description: "Mark yourself as no longer away",
+    },
+    Command {
+        name: "/clear",
+        args: "",
+        description: "Clear the message pane (only on your screen)",
+    },
+    Command {
+        name: "/close",
+        args: "[message]",
+        description: "Leave the server shown in this tab (Ctrl+PageUp/PageDown switch tabs)",
+    },
+    Command {
+        name: "/color",
+        args: "[color]",
+        description: "Pick the color of your name, e.g. lightblue or #ff8800. No color goes back to the default",
+    },
+    Command {
+        name: "/connect",
+        args: "<address> [password]",
+        description: "Open a connection to another server in a new tab",
+    },
+    Command {
+        name: "/delete",
+        args: "<message_id>",
+        description: "Delete one of your messages",
+    },
+    Command {
+        name: "/edit",
+        args: "<message_id> <text>",
+        description: "Replace the text of one of your messages",
+    },
+    Command {
+        name: "/events",
+        args: "",
+        description: "Hide or show join, leave and status messages",
+    },
+    Command {
+        name: "/grep",
+        args: "<text>",
+        description: "Search every room's history on the server, including messages too old to show (admins only)",
+    },
+    Command {
+        name: "/help",
+        args: "",
+        description: "Show this list of commands",
+    },
+    Command {
+        name: "/invite",
+        args: "",
+        description: "Show a link others can paste on their login screen to join this server",
+    },
+    Command {
+        name: "/join",
+        args: "<room>",
+        description: "Switch to another chat room",
+    },
+    Command {
+        name: "/kick",
+        args: "<username> [reason]",
+        description: "Disconnect a user from the server (admins only)",
+    },
+    Command {
+        name: "/list",
+        args: "",
+        description: "List connected users and their ids (Alt+PageUp/PageDown scroll the user pane)",
+    },
+    Command {
+        name: "/me",
+        args: "<action>",
+        description: "Describe what you are doing, e.g. /me waves",
+    },
+    Command {
+        name: "/mute",
+        args: "[username]",
+        description: "Hide a user's messages on your screen until you /unmute them. No name lists who is muted",
+    },
+    Command {
+        name: "/name",
+        args: "<username>",
+        description: "Change your username, spaces allowed (alias /nick)",
+    },
+    Command {
+        name: "/ping",
+        args: "",
+        description: "Measure how long the server takes to answer",
+    },
+    Command {
+        name: "/quit",
+        args: "[message]",
+        description: "Leave the chat, optionally saying goodbye",
+    },
+    Command {
+        name: "/raw",
+        args: "<text>",
+        description: "Send text as a message even if it starts with '/' (or type //text)",
+    },
+    Command {
+        name: "/reply",
+        args: "<message_id> <text>",
+        description: "Answer a message, quoting it above yours",
+    },
+    Command {
+        name: "/resend",
+        args: "",
+        description: "Send messages marked as not sent again",
+    },
+    Command {
+        name: "/roll",
+        args: "[dice]",
+        description: "Roll dice for everyone in the room to see, e.g. /roll 2d6 (default 1d6)",
+    },
+    Command {
+        name: "/save",
+        args: "[path]",
+        description: "Add the messages in this tab to the end of a text file (default: a new file in download_dir)",
+    },
+    Command {
+        name: "/search",
+        args: "[text]",
+        description: "Highlight messages containing text, n/N to move between them. No text clears it",
+    },
+    Command {
+        name: "/sendfile",
+        args: "<username> <path>",
+        description: "Send a file of up to 10 MB to one user",
+    },
+    Command {
+        name: "/stats",
+        args: "[username]",
+        description: "Show how many messages you or another user sent this session",
+    },
+    Command {
+        name: "/theme",
+        args: "[dark|light]",
+        description: "Switch colors for a dark or light terminal. No name goes back to your config's",
+    },
+    Command {
+        name: "/unmute",
+        args: "<username>",
+        description: "Show a muted user's messages again",
+    },
+    Command {
+        name: "/whisper",
+        args: "<username> <message>",
+        description: "Send a private message to one user (alias /w)",
+    },
+    Command {
+        name: "/whois",
+        args: "<username>",
+        description: "Show when a user connected, how many messages they sent and their former names. Admins also see their address",
+    },
+];
+
+// Text following the first `count` words of a command, with its spacing kept
+fn remainder(command: &str, count: usize) -> &str {
+    let mut rest = command.trim_start();
+    for _ in 0..count {
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        rest = rest[end..].trim_start();
     }
+    rest
+}
+
+// Colors a user's name can be drawn in
+const USER_COLORS: &[Color] = &[
+    Color::Cyan,
+    Color::Green,
+    Color::Yellow,
+    Color::Blue,
+    Color::Magenta,
+    Color::LightRed,
+    Color::LightGreen,
+    Color::LightYellow,
+    Color::LightBlue,
+    Color::LightMagenta,
+    Color::LightCyan,
+];
+
+// The color a user picked with /color, or a stable one from hashing
+// their id
+fn user_color(s: &ClientState, uid: u32) -> Color {
+    let hash = uid.wrapping_mul(0x9E37_79B1) >> 16;
+    s.name_colors.get(&uid).copied().unwrap_or(USER_COLORS[hash as usize % USER_COLORS.len()])
+}
+
+// A user's current name, or the one stored with the message once they've left
+fn display_name(s: &ClientState, uid: u32, stored: &str) -> String {
+    s.users.get(&uid).map_or(stored, String::as_str).to_string()
+}
+
+// Build the line shown for an entry, along with how far continuation rows
+// are indented so wrapped text lines up after the sender's name
+fn entry_line(entry: &ChatEntry, s: &ClientState, colors: &Colors) -> (Line<'static>, usize) {
+    let (prefix, body) = match entry {
+        ChatEntry::Message { uid, sender_id, sender_name, timestamp, text, edited, action, delivery, .. } => {
+            let sender_name = display_name(s, *sender_id, sender_name);
+            // Ids are only known once the server has stored the message
+            let id = if *uid == 0 { String::from("#…") } else { format!("#{}", uid) };
+            let time = Span::raw(format!("[{}] {} ", format_time(*timestamp), id)).fg(colors.timestamp);
+            // Our own messages stand out in the accent color
+            let text_color = if *sender_id == s.uid { colors.own } else { colors.text };
+            let (prefix, mut body) = if *action {
+                (
+                    vec![
+                        time,
+                        Span::raw("* ").italic(),
+                        Span::raw(sender_name).fg(user_color(s, *sender_id)).italic(),
+                        Span::raw(" "),
+                    ],
+                    markdown::spans(text, Style::new().fg(text_color).italic()),
+                )
+            }
+            else {
+                (
+                    vec![
+                        time,
+                        Span::raw(format!("({})", sender_name)).fg(user_color(s, *sender_id)),
+                        Span::raw(" "),
+                    ],
+                    markdown::spans(text, Style::new().fg(text_color)),
+                )
+            };
+            if *edited {
+                body.push(Span::raw(" (edited)").fg(colors.timestamp));
+            }
+            match delivery {
+                Delivery::Received => (),
+                Delivery::Pending { .. } => body.push(Span::raw(" (sending…)").fg(colors.timestamp)),
+                Delivery::Confirmed => body.push(Span::raw(" ✓").fg(colors.timestamp)),
+                Delivery::Failed => body.push(Span::raw(" ✗ not sent, /resend to retry").fg(colors.error)),
+            }
+            (prefix, body)
+        },
+        ChatEntry::Whisper { sender_id, target_id, sender_name, timestamp, text } => {
+            let (label, uid, name) = if *sender_id == s.uid {
+                let target = s.users.get(target_id)
+                    .cloned()
+                    .unwrap_or_else(|| target_id.to_string());
+                ("DM to", *target_id, target)
+            }
+            else {
+                ("DM from", *sender_id, display_name(s, *sender_id, sender_name))
+            };
+            (
+                vec![
+                    Span::raw(format!("[{}] ", format_time(*timestamp))).fg(colors.timestamp),
+                    Span::raw(format!("[{} ", label)).fg(colors.whisper),
+                    Span::raw(name).fg(user_color(s, uid)),
+                    Span::raw("] ").fg(colors.whisper),
+                ],
+                markdown::spans(text, Style::new().fg(colors.whisper)),
+            )
+        },
+        ChatEntry::System(text) => (vec![], vec![Span::raw(format!("— {}", text)).fg(colors.system).dim().italic()]),
+        ChatEntry::Notice(text) => (vec![], vec![Span::raw(text.clone()).fg(colors.system)]),
+        // Set apart from the chat, with every line after the label
+        ChatEntry::Motd(text) => (
+            vec![Span::raw("MOTD │ ").fg(colors.timestamp)],
+            markdown::spans(text, Style::new().fg(colors.text).bold()),
+        ),
+        ChatEntry::Transfer(transfer) => (vec![], vec![Span::raw(transfer.summary()).fg(colors.system)]),
+    };
+
+    let indent = prefix.iter().map(Span::width).sum();
+    let mut spans = prefix;
+    spans.extend(body);
+    (Line::from(spans), indent)
+}
+
+// The quote shown above a reply, e.g. "↳ replying to alice: hello"
+fn reply_line(parent: u32, s: &ClientState, colors: &Colors) -> Line<'static> {
+    let quote = s.messages.iter().find_map(|entry| match entry {
+        ChatEntry::Message { uid, sender_id, sender_name, text, .. } if *uid == parent => {
+            Some((*sender_id, display_name(s, *sender_id, sender_name), text))
+        },
+        _ => None,
+    });
+
+    let line = match quote {
+        Some((sender_id, name, text)) => {
+            let mut snippet: String = text.chars().take(REPLY_SNIPPET_LEN).map(|c| if c == '\n' { ' ' } else { c }).collect();
+            if text.chars().count() > REPLY_SNIPPET_LEN {
+                snippet.push('…');
+            }
+            Line::from(vec![
+                Span::raw("  ↳ replying to "),
+                Span::raw(name).fg(user_color(s, sender_id)),
+                Span::raw(format!(": {}", snippet)),
+            ])
+        },
+        // Sent before we joined, or since deleted
+        None => Line::from("  ↳ (reply to earlier message)"),
+    };
+    line.fg(colors.timestamp).italic()
+}
+
+// The display rows of an entry once wrapped to the pane width
+fn entry_rows(entry: &ChatEntry, s: &ClientState, colors: &Colors, right_align_own: bool, width: usize) -> Vec<Line<'static>> {
+    let own = match entry {
+        ChatEntry::Message { sender_id, .. } | ChatEntry::Whisper { sender_id, .. } => *sender_id == s.uid,
+        _ => false,
+    };
+    let wrap = |line, indent| {
+        if own && right_align_own {
+            wrap::wrap_right(line, width)
+        }
+        else {
+            wrap::wrap_line(line, width, indent)
+        }
+    };
+
+    let mut rows = match entry {
+        ChatEntry::Message { reply_to: Some(parent), .. } => wrap(reply_line(*parent, s, colors), 4),
+        _ => Vec::new(),
+    };
+    let (line, indent) = entry_line(entry, s, colors);
+    rows.extend(wrap(line, indent));
+    rows
+}
+
+// "Seen by alice, bob" for our latest message, along with its index.
+// Anyone who has seen a later message has seen ours too.
+fn seen_by(s: &ClientState) -> Option<(usize, String)> {
+    let (index, uid) = s.messages.iter().enumerate().rev().find_map(|(index, entry)| match entry {
+        ChatEntry::Message { uid, sender_id, .. } if *sender_id == s.uid && *uid != 0 => Some((index, *uid)),
+        _ => None,
+    })?;
+    let mut names: Vec<&str> = s.receipts
+        .iter()
+        .filter(|(message, _)| **message >= uid)
+        .flat_map(|(_, readers)| readers)
+        .filter(|reader| **reader != s.uid)
+        .filter_map(|reader| s.users.get(reader).map(String::as_str))
+        .collect();
+    if names.is_empty() {
+        return None;
+    }
+    names.sort();
+    names.dedup();
+    Some((index, format!("Seen by {}", names.join(", "))))
+}
+
+// The text /search looks through
+fn entry_text(entry: &ChatEntry) -> String {
+    match entry {
+        ChatEntry::Message { text, .. } | ChatEntry::Whisper { text, .. } => text.clone(),
+        ChatEntry::System(text) | ChatEntry::Notice(text) | ChatEntry::Motd(text) => text.clone(),
+        ChatEntry::Transfer(transfer) => transfer.summary(),
+    }
+}
+
+// Split a row's spans so every occurrence of `query` is drawn in `style`.
+// `query` is lowercase.
+fn highlight(row: Line<'static>, query: &str, style: Style) -> Line<'static> {
+    let query: Vec<char> = query.chars().collect();
+    let same = |a: char, b: char| a.to_lowercase().eq(b.to_lowercase());
+
+    let mut spans = Vec::new();
+    for span in row.spans {
+        let chars: Vec<char> = span.content.chars().collect();
+        let mut start = 0;
+        let mut pos = 0;
+        while !query.is_empty() && pos + query.len() <= chars.len() {
+            if chars[pos..pos + query.len()].iter().zip(&query).all(|(a, b)| same(*a, *b)) {
+                if pos > start {
+                    spans.push(Span::styled(chars[start..pos].iter().collect::<String>(), span.style));
+                }
+                let found: String = chars[pos..pos + query.len()].iter().collect();
+                spans.push(Span::styled(found, span.style.patch(style)));
+                pos += query.len();
+                start = pos;
+            }
+            else {
+                pos += 1;
+            }
+        }
+        if start < chars.len() {
+            spans.push(Span::styled(chars[start..].iter().collect::<String>(), span.style));
+        }
+    }
+    let mut line = Line::from(spans).style(row.style);
+    line.alignment = row.alignment;
+    line
+}
+
+// e.g. "alice and bob are typing…"
+fn typing_line(names: &[&str]) -> String {
+    match names {
+        [] => String::new(),
+        [name] => format!("{} is typing…", name),
+        [first, second] => format!("{} and {} are typing…", first, second),
+        _ => String::from("Several people are typing…"),
+    }
+}
+
+fn usage(name: &str) -> String {
+    match COMMANDS.iter().find(|command| command.name == name) {
+        Some(command) => format!("Usage: {} {}", command.name, command.args).trim_end().to_string(),
+        None => format!("Unknown command: {} — type /help", name),
+    }
+}
+
+// Usernames matching the word being completed with Tab. Until the first
+// Tab that picks one, only the part they share has been filled in.
+struct Completion {
+    // Where the word starts in the input, in chars
+    start: usize,
+    // Filled in as `@name`, for a mention
+    mention: bool,
+    names: Vec<String>,
+    selected: Option<usize>,
 }
 
 pub struct Chat {
     input: String,
     character_index: usize,
-    stream: BufWriter<TcpStream>,
-    user_id: u32,
+
+    // Every open connection. The stream and state of the one on screen
+    // are also kept below.
+    tabs: Vec<Tab>,
+    active_tab: usize,
+    stream: SharedWriter,
+    state: Arc<Mutex<ClientState>>,
+
+    // Used for connections opened with /connect
+    tls: Option<Arc<ClientConfig>>,
+    settings: TabSettings,
+
+    // Number of lines the message pane is scrolled up from the bottom, and
+    // messages that arrived below the view since
+    scroll_offset: usize,
+    unread: usize,
+    // Message count and pane height as of the last draw
+    message_count: usize,
+    page_height: usize,
+
+    // Whether the oldest entry was in view as of the last draw, and how
+    // many entries older history had put above the rest and the cap had
+    // dropped from the top by then
+    at_top: bool,
+    prepended: usize,
+    dropped: usize,
+
+    // Where the user list was drawn and whose name is on each row, so
+    // clicks can be matched to users. Rows showing how many more users
+    // there are have no one on them.
+    users_area: Rect,
+    user_rows: Vec<Option<u32>>,
+
+    // Users scrolled past at the top of the user list
+    users_scroll: usize,
+
+    // When we last told others we are typing, and when the input last changed
+    typing_sent: Option<Instant>,
+    last_edit: Instant,
+
+    // Newest message at the bottom of the view as of the last draw, and
+    // when we last sent a read receipt
+    newest_visible: Option<u32>,
+    receipt_sent: Option<Instant>,
+
+    // Whether joins, leaves and other events are shown, toggled with /events
+    show_events: bool,
+
+    // Last nonce used to match a sent message to the server's Ack
+    last_nonce: u32,
+
+    // Lowercase text being searched for, which match is highlighted
+    // counting up from the newest, and whether the view should jump to it
+    search: Option<String>,
+    search_match: usize,
+    search_jump: bool,
+
+    // User count last shown in the terminal title
+    title_count: Option<usize>,
+
+    // Esc was pressed with text in the input box, and the next key says
+    // whether to throw it away and quit
+    confirm_quit: bool,
+
+    // Set by Tab and dropped by any other key
+    completion: Option<Completion>,
+
+    // Previously submitted inputs, oldest first. While browsing them the
+    // index points at the one shown and the unsent input is kept aside.
+    history: Vec<String>,
+    history_index: Option<usize>,
+    draft: String,
+
+    colors: Colors,
+    right_align_own: bool,
+
+    // Colors from the config file, which /theme goes back to without a name
+    config_colors: Colors,
+
+    // Built-in and configured macros, by name without the slash
+    macros: HashMap<String, String>,
 }
 
 impl Chat {
-    pub fn new(stream: BufWriter<TcpStream>, uid: u32) -> Self {
+    fn new(
+        tab: Tab,
+        colors: Colors,
+        right_align_own: bool,
+        tls: Option<Arc<ClientConfig>>,
+        settings: TabSettings,
+        macros: HashMap<String, String>,
+    ) -> Self {
         Self {
             input: String::new(),
             character_index: 0,
-            stream: stream,
-            user_id: uid,
+            stream: tab.stream.clone(),
+            state: tab.state.clone(),
+            tabs: vec![tab],
+            active_tab: 0,
+            tls,
+            settings,
+            scroll_offset: 0,
+            unread: 0,
+            message_count: 0,
+            page_height: 0,
+            at_top: false,
+            prepended: 0,
+            dropped: 0,
+            users_area: Rect::default(),
+            user_rows: Vec::new(),
+            users_scroll: 0,
+            typing_sent: None,
+            last_edit: Instant::now(),
+            newest_visible: None,
+            receipt_sent: None,
+            show_events: true,
+            last_nonce: 0,
+            search: None,
+            search_match: 0,
+            search_jump: false,
+            title_count: None,
+            confirm_quit: false,
+            completion: None,
+            history: Vec::new(),
+            history_index: None,
+            draft: String::new(),
+            colors,
+            right_align_own,
+            config_colors: colors,
+            macros,
+        }
+    }
+
+    fn switch_tab(&mut self, index: usize) {
+        // Others shouldn't see us typing on a server we've left the view of
+        if self.typing_sent.take().is_some() {
+            let packet = Packet {
+                packet_type: PacketType::StoppedTyping,
+                user_id: self.uid(),
+                ..Default::default()
+            };
+            let _ = write_packet(&mut *self.stream.lock().unwrap(), &packet);
+        }
+
+        self.active_tab = index;
+        self.stream = self.tabs[index].stream.clone();
+        self.state = self.tabs[index].state.clone();
+        self.scroll_offset = 0;
+        self.unread = 0;
+        self.message_count = 0;
+        let s = self.state.lock().unwrap();
+        (self.prepended, self.dropped) = (s.prepended, s.dropped);
+        drop(s);
+        self.title_count = None;
+    }
+
+    fn next_tab(&mut self) {
+        self.switch_tab((self.active_tab + 1) % self.tabs.len());
+    }
+
+    fn previous_tab(&mut self) {
+        self.switch_tab((self.active_tab + self.tabs.len() - 1) % self.tabs.len());
+    }
+
+    // Log in to another server with our current name and switch to it
+    fn connect(&mut self, address: &str, password: &str) {
+        let server = Server {
+            address: login::server_address(address),
+            tls: self.tls.clone(),
+            password: password.to_string(),
+        };
+        let username = self.state.lock().unwrap().username.clone();
+        match server.connect(&username) {
+            Ok((uid, reader, writer)) => {
+                self.tabs.push(Tab::start(
+                    server,
+                    uid,
+                    username,
+                    reader,
+                    writer,
+                    &self.settings,
+                ));
+                self.switch_tab(self.tabs.len() - 1);
+            },
+            Err(error) => self.local_message(format!("Error: Failed to connect to {}: {}", server.address, error)),
+        }
+    }
+
+    // Leave the server on screen. Closing the last one quits.
+    fn close_tab(&mut self, index: usize, message: &str) {
+        if self.tabs.len() == 1 {
+            self.quit(message);
+        }
+        self.remove_tab(index).close(message);
+    }
+
+    // Take a tab out, staying on the one shown unless it was the one
+    // removed. There must be another tab left.
+    fn remove_tab(&mut self, index: usize) -> Tab {
+        let tab = self.tabs.remove(index);
+        if index < self.active_tab {
+            self.active_tab -= 1;
+        }
+        else if index == self.active_tab {
+            self.typing_sent = None;
+            self.switch_tab(index.min(self.tabs.len() - 1));
         }
+        tab
+    }
+
+    fn scroll_up(&mut self, lines: usize) {
+        self.scroll_offset = self.scroll_offset.saturating_add(lines);
+    }
+
+    fn scroll_down(&mut self, lines: usize) {
+        self.scroll_offset = self.scroll_offset.saturating_sub(lines);
+    }
+
+    // Drawing clamps this once it knows how many users fit
+    fn scroll_users(&mut self, up: bool, lines: usize) {
+        self.users_scroll = match up {
+            true => self.users_scroll.saturating_sub(lines),
+            false => self.users_scroll.saturating_add(lines),
+        };
+    }
+
+    // Our id changes whenever the connection is re-established
+    fn uid(&self) -> u32 {
+        self.state.lock().unwrap().uid
+    }
+
+    // Show a line in the message pane without sending anything
+    fn local_message(&self, text: String) {
+        self.state.lock().unwrap().messages.push(ChatEntry::Notice(text));
     }
 
     fn clamp_cursor(&self, pos: usize) -> usize {
@@ -100,6 +902,26 @@ impl Chat {
         let index = self.byte_index();
         self.input.insert(index, c);
         self.move_cursor_right();
+        self.last_edit = Instant::now();
+    }
+
+    fn move_cursor_word_left(&mut self) {
+        self.character_index = input::word_left(&self.input, self.character_index);
+    }
+
+    fn move_cursor_word_right(&mut self) {
+        self.character_index = input::word_right(&self.input, self.character_index);
+    }
+
+    fn clear_input(&mut self) {
+        self.input.clear();
+        self.character_index = 0;
+        self.last_edit = Instant::now();
+    }
+
+    fn delete_word(&mut self) {
+        self.character_index = input::delete_word(&mut self.input, self.character_index);
+        self.last_edit = Instant::now();
     }
 
     fn delete_char(&mut self) {
@@ -110,144 +932,1511 @@ impl Chat {
 
             self.input = before_cursor.chain(after_cursor).collect();
             self.move_cursor_left();
+            self.last_edit = Instant::now();
         }
     }
 
-    fn submit_message(&mut self) {
-        let start = match self.input.chars().nth(0) {
-            Some(c) => c,
-            None => '!'
-        };
-
-        match start {
-            '/' => {
-                let packet = self.parse_command(self.input.clone());
-                match packet {
-                    None => (),
-                    Some(packet) => {
-                        let data = serde_json::to_string(&packet)
-                            .expect("[ERROR] Failed to serialize packet");
-                        let _ = self.stream.write(data.as_bytes());
-                        self.stream.flush().expect("[ERROR] Failed to send message");
-                    }
-                }
+    // Complete the username before the cursor. The first Tab fills in as
+    // much as every matching name shares, then each Tab after it swaps in
+    // the next match, or the previous one when `forward` is false.
+    fn complete_name(&mut self, forward: bool) {
+        let completion = match self.completion.take() {
+            Some(mut completion) => {
+                let count = completion.names.len();
+                completion.selected = Some(match completion.selected {
+                    Some(index) if forward => (index + 1) % count,
+                    Some(index) => (index + count - 1) % count,
+                    None if forward => 0,
+                    None => count - 1,
+                });
+                completion
             },
-            '!' => (),
-            _ => {
-                let packet = Packet {
-                    packet_type: PacketType::NewMessage,
-                    user_id: self.user_id,
-                    contents: self.input.clone(),
+            None => {
+                let start = input::word_start(&self.input, self.character_index);
+                let word: String = self.input.chars().skip(start).take(self.character_index - start).collect();
+                let prefix = word.trim_start_matches('@').to_lowercase();
+                let mut names: Vec<String> = self.state.lock().unwrap().users
+                    .values()
+                    .filter(|name| name.to_lowercase().starts_with(&prefix))
+                    .cloned()
+                    .collect();
+                names.sort_by_key(|name| name.to_lowercase());
+                names.dedup();
+                if names.is_empty() {
+                    return;
+                }
+
+                // Names at the start of a line are most likely mentions
+                let mention = word.starts_with('@') || start == 0;
+                let common = input::common_prefix(&names);
+                let selected = if names.len() > 1 && common.chars().count() > prefix.chars().count() {
+                    None
+                }
+                else if forward {
+                    Some(0)
+                }
+                else {
+                    Some(names.len() - 1)
                 };
-                let data = serde_json::to_string(&packet)
-                    .expect("[ERROR] Failed to serialize packet");
-                let _ = self.stream.write(data.as_bytes());
-                self.stream.flush().expect("[ERROR] Failed to send message");
+                Completion { start, mention, names, selected }
+            },
+        };
+
+        let mut text = match completion.selected {
+            Some(index) => format!("{} ", completion.names[index]),
+            None => input::common_prefix(&completion.names),
+        };
+        if completion.mention {
+            text.insert(0, '@');
+        }
+        self.input = self.input
+            .chars()
+            .take(completion.start)
+            .chain(text.chars())
+            .chain(self.input.chars().skip(self.character_index))
+            .collect();
+        self.character_index = completion.start + text.chars().count();
+        self.last_edit = Instant::now();
+        self.completion = Some(completion);
+    }
+
+    fn set_input(&mut self, text: String) {
+        self.input = text;
+        self.character_index = self.input.chars().count();
+    }
+
+    fn history_previous(&mut self) {
+        let index = match self.history_index {
+            Some(0) => return,
+            Some(index) => index - 1,
+            None if self.history.is_empty() => return,
+            None => {
+                self.draft = std::mem::take(&mut self.input);
+                self.history.len() - 1
+            },
+        };
+        self.history_index = Some(index);
+        self.set_input(self.history[index].clone());
+    }
+
+    fn history_next(&mut self) {
+        let Some(index) = self.history_index else {
+            return;
+        };
+        if index + 1 < self.history.len() {
+            self.history_index = Some(index + 1);
+            self.set_input(self.history[index + 1].clone());
+        }
+        else {
+            // Back to what was being typed before browsing
+            self.history_index = None;
+            let draft = std::mem::take(&mut self.draft);
+            self.set_input(draft);
+        }
+    }
+
+    fn remember_input(&mut self) {
+        self.history_index = None;
+        self.draft.clear();
+
+        // Passwords shouldn't come back up with the arrow keys
+        let tokens: Vec<&str> = self.input.split_whitespace().collect();
+        let entry = match tokens[..] {
+            ["/connect", address, _, ..] => format!("/connect {}", address),
+            _ => self.input.clone(),
+        };
+        if entry.is_empty() || self.history.last() == Some(&entry) {
+            return;
+        }
+        self.history.push(entry);
+        if self.history.len() > INPUT_HISTORY_LEN {
+            self.history.remove(0);
+        }
+    }
+
+    // Text typed as a chat message, with macros and emoji shortcodes
+    // expanded. Escaped and /raw messages skip this.
+    fn chat_text(&self, text: &str) -> String {
+        emoji::expand(&macros::expand(text, &self.macros))
+    }
+
+    fn new_message(&self, contents: String) -> Packet {
+        Packet {
+            packet_type: PacketType::NewMessage,
+            user_id: self.uid(),
+            contents,
+            ..Default::default()
+        }
+    }
+
+    fn submit_message(&mut self) {
+        let packet = if let Some(text) = self.input.strip_prefix("//") {
+            // A doubled slash escapes a message that starts with '/'
+            Some(self.new_message(format!("/{}", text)))
+        }
+        else if self.input.starts_with('/') {
+            self.parse_command(self.input.clone())
+        }
+        else if !self.input.is_empty() {
+            Some(self.new_message(self.chat_text(&self.input)))
+        }
+        else {
+            None
+        };
+
+        if let Some(packet) = packet {
+            // Keep the input around so it can be sent once we are back
+            // online. Commands that stay on this side still run.
+            if self.state.lock().unwrap().connection_state != ConnectionState::Connected {
+                self.local_message(String::from("Not connected, message not sent"));
+                return;
+            }
+
+            let result = if packet.packet_type == PacketType::NewMessage {
+                self.send_chat_message(packet)
+            }
+            else {
+                write_packet(&mut *self.stream.lock().unwrap(), &packet)
+            };
+
+            // The connection is gone. Keep the input so it can be sent
+            // again once the listener has reconnected.
+            if let Err(error) = result {
+                self.local_message(format!("Failed to send ({}), reconnecting. Press Enter to try again", error));
+                return;
             }
         }
 
+        self.remember_input();
         self.input.clear();
         self.character_index = 0;
     }
 
-    fn parse_command(&mut self, command: String) -> Option<Packet> {
-        let tokens: Vec<&str> = command.split_whitespace().collect();
-        
-        if tokens.len() < 2 {
-            return None
+    // Show a chat message straight away and send it. It stays pending
+    // until the server acknowledges it, and is taken back off the screen
+    // if it couldn't be written.
+    fn send_chat_message(&mut self, mut packet: Packet) -> io::Result<()> {
+        self.last_nonce += 1;
+        let nonce = self.last_nonce;
+        packet.nonce = nonce;
+
+        // The entry has to exist before the Ack can arrive
+        {
+            let message = packet.message.clone().unwrap_or_default();
+            let mut s = self.state.lock().unwrap();
+            let entry = ChatEntry::Message {
+                uid: 0,
+                sender_id: s.uid,
+                sender_name: s.username.clone(),
+                timestamp: unix_time(),
+                text: packet.contents.trim().to_string(),
+                edited: false,
+                action: message.action,
+                reply_to: message.reply_to,
+                delivery: Delivery::Pending { nonce, since: Instant::now() },
+            };
+            s.messages.push(entry);
+        }
+
+        let result = write_packet(&mut *self.stream.lock().unwrap(), &packet);
+        if result.is_err() {
+            self.state.lock().unwrap().messages.retain(|entry| !matches!(
+                entry,
+                ChatEntry::Message { delivery: Delivery::Pending { nonce: pending, .. }, .. } if *pending == nonce
+            ));
         }
+        result
+    }
 
+    // Send every message that failed again, moving them to the bottom
+    fn resend_failed(&mut self) {
+        let failed: Vec<ChatEntry> = {
+            let mut s = self.state.lock().unwrap();
+            let (failed, kept) = std::mem::take(&mut s.messages)
+                .into_iter()
+                .partition(|entry| matches!(entry, ChatEntry::Message { delivery: Delivery::Failed, .. }));
+            s.messages = kept;
+            failed
+        };
+        if failed.is_empty() {
+            self.local_message(String::from("No messages to resend"));
+        }
+
+        let mut failed = failed.into_iter();
+        while let Some(entry) = failed.next() {
+            if let ChatEntry::Message { text, action, reply_to, .. } = &entry {
+                let mut packet = self.new_message(text.clone());
+                packet.message = Some(Message {
+                    action: *action,
+                    reply_to: *reply_to,
+                    ..Default::default()
+                });
+
+                // Put back what is left to send for the next /resend
+                if let Err(error) = self.send_chat_message(packet) {
+                    let mut s = self.state.lock().unwrap();
+                    s.messages.push(entry);
+                    s.messages.extend(failed);
+                    s.messages.push(ChatEntry::Notice(format!("Error: Failed to send message: {}", error)));
+                    return;
+                }
+            }
+        }
+    }
+
+    // Give up on messages the server never confirmed
+    fn update_pending(&self) {
+        for tab in &self.tabs {
+            let mut s = tab.state.lock().unwrap();
+            s.expire_ping();
+            for entry in s.messages.iter_mut() {
+                if let ChatEntry::Message { delivery, .. } = entry &&
+                    let Delivery::Pending { since, .. } = delivery &&
+                    since.elapsed() > ACK_TIMEOUT
+                {
+                    *delivery = Delivery::Failed;
+                }
+            }
+        }
+    }
+
+    // Tell every server we are leaving, with an optional parting message
+    // for the others, and exit
+    fn quit(&self, message: &str) -> ! {
+        for tab in &self.tabs {
+            tab.close(message);
+        }
+        restore_terminal();
+        process::exit(0);
+    }
+
+    // Ids are shown as `#12`, so accept them with the hash too
+    fn parse_message_id(&mut self, token: &str) -> Option<u32> {
+        let uid = token.trim_start_matches('#').parse().ok();
+        if uid.is_none() {
+            self.local_message(format!("Error: Invalid message id {}", token));
+        }
+        uid
+    }
+
+    fn find_user(&mut self, name: &str) -> Option<u32> {
+        let uid = self.state.lock().unwrap().users
+            .iter()
+            .find(|(_, user)| user.as_str() == name)
+            .map(|(uid, _)| *uid);
+        if uid.is_none() {
+            self.local_message(format!("Error: No user named {}", name));
+        }
+        uid
+    }
+
+    // Split the user a command is aimed at from the text after them. Names
+    // may contain spaces, so the longest one that starts the arguments wins.
+    fn find_target<'a>(&mut self, command: &'a str) -> Option<(u32, &'a str)> {
+        let rest = remainder(command, 1);
+        let target = self.state.lock().unwrap().users
+            .iter()
+            .filter(|(_, name)| {
+                rest.strip_prefix(name.as_str())
+                    .is_some_and(|after| after.is_empty() || after.starts_with(char::is_whitespace))
+            })
+            .max_by_key(|(_, name)| name.len())
+            .map(|(uid, name)| (*uid, rest[name.len()..].trim_start()));
+        if target.is_none() {
+            let name = rest.split_whitespace().next().unwrap_or_default();
+            self.local_message(format!("Error: No user named {}", name));
+        }
+        target
+    }
+
+    fn parse_command(&mut self, command: String) -> Option<Packet> {
+        let tokens: Vec<&str> = command.split_whitespace().collect();
         let first = tokens[0];
 
         match first {
-            "/name" => {
+            "/help" => {
+                self.local_message(String::from("Available commands:"));
+                for command in COMMANDS {
+                    let syntax = format!("{} {}", command.name, command.args);
+                    self.local_message(format!("  {} - {}", syntax.trim_end(), command.description));
+                }
+                let mut names: Vec<String> = self.macros.keys().map(|name| format!("/{}", name)).collect();
+                names.sort();
+                self.local_message(format!("Macros (also work inside messages): {}", names.join(", ")));
+                None
+            },
+            "/search" => {
+                let query = remainder(&command, 1).to_lowercase();
+                self.search = None;
+                if !query.is_empty() {
+                    let found = self.search_matches(&self.state.lock().unwrap(), &query).len();
+                    if found == 0 {
+                        self.local_message(format!("No messages contain \"{}\"", remainder(&command, 1)));
+                    }
+                    else {
+                        self.search = Some(query);
+                        self.search_match = 0;
+                        self.search_jump = true;
+                    }
+                }
+                None
+            },
+            "/events" => {
+                self.show_events = !self.show_events;
+                let state = if self.show_events { "shown" } else { "hidden" };
+                self.local_message(format!("Join, leave and status messages are now {}", state));
+                None
+            },
+            "/invite" => {
+                let link = format!("{}{}", login::LINK_SCHEME, self.tabs[self.active_tab].name);
+                self.local_message(format!("Invite others with {}", link));
+                None
+            },
+            "/theme" => {
+                match tokens.get(1) {
+                    None => {
+                        self.colors = self.config_colors;
+                        self.local_message(String::from("Using the colors from your config file"));
+                    },
+                    Some(name) => match Theme::parse(name) {
+                        Some(theme) => {
+                            self.colors = theme.colors();
+                            self.local_message(format!("Switched to the {} theme", name.to_lowercase()));
+                        },
+                        None => self.local_message(format!("Error: Unknown theme {}, try dark or light", name)),
+                    },
+                }
+                None
+            },
+            "/quit" => self.quit(remainder(&command, 1)),
+            "/connect" if tokens.len() >= 2 => {
+                self.connect(tokens[1], tokens.get(2).copied().unwrap_or_default());
+                None
+            },
+            "/close" => {
+                self.close_tab(self.active_tab, remainder(&command, 1));
+                None
+            },
+            "/resend" => {
+                self.resend_failed();
+                None
+            },
+            "/save" => {
+                let result = {
+                    let s = self.state.lock().unwrap();
+                    let path = match remainder(&command, 1) {
+                        "" => save::default_path(&s),
+                        path => PathBuf::from(path),
+                    };
+                    save::save(&path, &s).map(|count| (path, count))
+                };
+                match result {
+                    Ok((path, count)) => {
+                        let plural = if count == 1 { "entry" } else { "entries" };
+                        self.local_message(format!("Saved {} {} to {}", count, plural, path.display()));
+                    },
+                    Err(error) => self.local_message(format!("Error: Failed to save: {}", error)),
+                }
+                None
+            },
+            "/clear" => {
+                self.state.lock().unwrap().messages.clear();
+                self.scroll_offset = 0;
+                None
+            },
+            "/list" => {
+                let users: Vec<String> = {
+                    let s = self.state.lock().unwrap();
+                    s.sorted_users()
+                        .into_iter()
+                        .map(|(uid, name)| match s.idle_for(uid) {
+                            Some(idle) => format!("  {} ({}, idle {})", name, uid, net::format_idle(idle)),
+                            None => format!("  {} ({})", name, uid),
+                        })
+                        .collect()
+                };
+
+                self.local_message(format!("Connected users ({}):", users.len()));
+                for line in users {
+                    self.local_message(line);
+                }
+                None
+            },
+            "/mute" if tokens.len() >= 2 => {
+                let name = remainder(&command, 1);
+                let uid = self.find_user(name)?;
+                let text = match uid == self.uid() {
+                    true => String::from("Error: You can't mute yourself"),
+                    false if self.state.lock().unwrap().muted.insert(uid) => {
+                        format!("Muted {}. Their messages are hidden until you /unmute them", name)
+                    },
+                    false => format!("{} is already muted", name),
+                };
+                self.local_message(text);
+                None
+            },
+            "/mute" => {
+                let names: Vec<String> = {
+                    let s = self.state.lock().unwrap();
+                    s.sorted_users()
+                        .into_iter()
+                        .filter(|(uid, _)| s.muted.contains(uid))
+                        .map(|(_, name)| name.to_string())
+                        .collect()
+                };
+                let text = match names.is_empty() {
+                    true => String::from("Nobody is muted"),
+                    false => format!("Muted: {}", names.join(", ")),
+                };
+                self.local_message(text);
+                None
+            },
+            "/unmute" if tokens.len() >= 2 => {
+                let name = remainder(&command, 1);
+                let uid = self.find_user(name)?;
+                let text = match self.state.lock().unwrap().muted.remove(&uid) {
+                    true => format!("Unmuted {}", name),
+                    false => format!("{} isn't muted", name),
+                };
+                self.local_message(text);
+                None
+            },
+            "/name" | "/nick" if tokens.len() >= 2 => {
+                match net::validate_name(remainder(&command, 1)) {
+                    Ok(name) => Some(Packet {
+                        packet_type: PacketType::UsernameChange,
+                        user_id: self.uid(),
+                        contents: name,
+                        ..Default::default()
+                    }),
+                    Err(reason) => {
+                        self.local_message(format!("Error: {}", reason));
+                        None
+                    },
+                }
+            },
+            "/join" if tokens.len() >= 2 => {
+                Some(Packet {
+                    packet_type: PacketType::JoinRoom,
+                    user_id: self.uid(),
+                    room: tokens[1].to_string(),
+                    ..Default::default()
+                })
+            },
+            "/me" if tokens.len() >= 2 => {
+                let mut packet = self.new_message(self.chat_text(remainder(&command, 1)));
+                packet.message = Some(Message {
+                    action: true,
+                    ..Default::default()
+                });
+                Some(packet)
+            },
+            "/raw" if tokens.len() >= 2 => {
+                // Send everything after the command verbatim
+                Some(self.new_message(remainder(&command, 1).to_string()))
+            },
+            "/whisper" | "/w" if tokens.len() >= 3 => {
+                let (target, text) = self.find_target(&command)?;
+                if text.is_empty() {
+                    self.local_message(usage("/whisper"));
+                    return None;
+                }
+                Some(Packet {
+                    packet_type: PacketType::PrivateMessage,
+                    user_id: self.uid(),
+                    contents: macros::expand(text, &self.macros),
+                    target,
+                    ..Default::default()
+                })
+            },
+            "/sendfile" if tokens.len() >= 3 => {
+                let (target, path) = self.find_target(&command)?;
+                if path.is_empty() {
+                    self.local_message(usage(first));
+                    return None;
+                }
+                let path = Path::new(path);
+                if let Err(error) = transfer::send_file(self.stream.clone(), self.state.clone(), target, path) {
+                    self.local_message(format!("Error: Failed to send {}: {}", path.display(), error));
+                }
+                None
+            },
+            "/kick" if tokens.len() >= 2 => {
+                let (target, reason) = self.find_target(&command)?;
+                Some(Packet {
+                    packet_type: PacketType::Kick,
+                    user_id: self.uid(),
+                    contents: reason.to_string(),
+                    target,
+                    ..Default::default()
+                })
+            },
+            "/whois" if tokens.len() >= 2 => {
+                Some(Packet {
+                    packet_type: PacketType::WhoisRequest,
+                    user_id: self.uid(),
+                    contents: remainder(&command, 1).to_string(),
+                    ..Default::default()
+                })
+            },
+            "/grep" if tokens.len() >= 2 => {
+                Some(Packet {
+                    packet_type: PacketType::Grep,
+                    user_id: self.uid(),
+                    contents: remainder(&command, 1).to_string(),
+                    ..Default::default()
+                })
+            },
+            "/away" => {
+                let reason = match remainder(&command, 1) {
+                    "" => "Away from keyboard",
+                    reason => reason,
+                };
                 Some(Packet {
-                    packet_type: PacketType::UsernameChange,
-                    user_id: self.user_id,
-                    contents: tokens[1].to_string(),
+                    packet_type: PacketType::StatusChange,
+                    user_id: self.uid(),
+                    contents: reason.to_string(),
+                    ..Default::default()
                 })
             },
-            _ => None
+            "/ping" => Some(self.state.lock().unwrap().start_ping()),
+            "/stats" => {
+                let target = match remainder(&command, 1) {
+                    "" => self.uid(),
+                    name => self.find_user(name)?,
+                };
+                Some(Packet {
+                    packet_type: PacketType::Stats,
+                    user_id: self.uid(),
+                    target,
+                    ..Default::default()
+                })
+            },
+            "/color" => {
+                Some(Packet {
+                    packet_type: PacketType::ColorChange,
+                    user_id: self.uid(),
+                    contents: remainder(&command, 1).to_string(),
+                    ..Default::default()
+                })
+            },
+            "/roll" => {
+                Some(Packet {
+                    packet_type: PacketType::DiceRoll,
+                    user_id: self.uid(),
+                    contents: tokens.get(1).unwrap_or(&"1d6").to_string(),
+                    ..Default::default()
+                })
+            },
+            "/back" => {
+                Some(Packet {
+                    packet_type: PacketType::StatusChange,
+                    user_id: self.uid(),
+                    ..Default::default()
+                })
+            },
+            "/reply" if tokens.len() >= 3 => {
+                let uid = self.parse_message_id(tokens[1])?;
+                let mut packet = self.new_message(self.chat_text(remainder(&command, 2)));
+                packet.message = Some(Message {
+                    reply_to: Some(uid),
+                    ..Default::default()
+                });
+                Some(packet)
+            },
+            "/edit" if tokens.len() >= 3 => {
+                let uid = self.parse_message_id(tokens[1])?;
+                Some(Packet {
+                    packet_type: PacketType::EditMessage,
+                    user_id: self.uid(),
+                    contents: macros::expand(remainder(&command, 2), &self.macros),
+                    message: Some(Message {
+                        uid,
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                })
+            },
+            "/delete" if tokens.len() >= 2 => {
+                let uid = self.parse_message_id(tokens[1])?;
+                Some(Packet {
+                    packet_type: PacketType::DeleteMessage,
+                    user_id: self.uid(),
+                    message: Some(Message {
+                        uid,
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                })
+            },
+            "/w" => {
+                self.local_message(usage("/whisper"));
+                None
+            },
+            "/nick" => {
+                self.local_message(usage("/name"));
+                None
+            },
+            // Anything else naming a macro is a message starting with it
+            _ if self.macros.contains_key(&first[1..]) => {
+                Some(self.new_message(self.chat_text(command.trim_start())))
+            },
+            _ => {
+                self.local_message(usage(first));
+                None
+            },
+        }
+    }
+
+    // Let others know whether we are composing a message. Commands aren't
+    // announced since they never show up in the chat.
+    fn update_typing(&mut self) {
+        let composing = !self.input.is_empty() &&
+            (!self.input.starts_with('/') || self.input.starts_with("//")) &&
+            self.last_edit.elapsed() < TYPING_IDLE;
+
+        let packet_type = match self.typing_sent {
+            Some(_) if !composing => {
+                self.typing_sent = None;
+                PacketType::StoppedTyping
+            },
+            Some(sent) if sent.elapsed() < TYPING_RESEND => return,
+            None if !composing => return,
+            _ => {
+                self.typing_sent = Some(Instant::now());
+                PacketType::Typing
+            },
+        };
+
+        let packet = Packet {
+            packet_type,
+            user_id: self.uid(),
+            ..Default::default()
+        };
+        let _ = write_packet(&mut *self.stream.lock().unwrap(), &packet);
+    }
+
+    // Ask for the page of messages before the oldest one we have once the
+    // user has scrolled up to it
+    fn update_history(&mut self) {
+        if !self.at_top {
+            return;
+        }
+        let before = {
+            let mut s = self.state.lock().unwrap();
+            if s.history_pending || s.history_complete || s.connection_state != ConnectionState::Connected {
+                return;
+            }
+            let Some(before) = s.oldest_message() else {
+                return;
+            };
+            s.history_pending = true;
+            before
+        };
+
+        let packet = Packet {
+            packet_type: PacketType::HistoryRequest,
+            user_id: self.uid(),
+            message: Some(Message {
+                uid: before,
+                ..Default::default()
+            }),
+            limit: HISTORY_PAGE,
+            ..Default::default()
+        };
+        if write_packet(&mut *self.stream.lock().unwrap(), &packet).is_err() {
+            self.state.lock().unwrap().history_pending = false;
+        }
+    }
+
+    // Tell the server the newest message we've shown, if it is one we
+    // haven't reported yet. Nothing counts as seen while unfocused.
+    fn update_receipt(&mut self) {
+        if self.receipt_sent.is_some_and(|sent| sent.elapsed() < RECEIPT_INTERVAL) {
+            return;
+        }
+        let Some(uid) = self.newest_visible else {
+            return;
+        };
+        {
+            let mut s = self.state.lock().unwrap();
+            if !s.focused || uid <= s.receipt_sent {
+                return;
+            }
+            s.receipt_sent = uid;
+        }
+
+        let packet = Packet {
+            packet_type: PacketType::ReadReceipt,
+            user_id: self.uid(),
+            message: Some(Message {
+                uid,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let _ = write_packet(&mut *self.stream.lock().unwrap(), &packet);
+        self.receipt_sent = Some(Instant::now());
+    }
+
+    // The wheel scrolls whichever pane it is over, and clicking a user
+    // starts a whisper
+    fn handle_mouse(&mut self, mouse: MouseEvent) {
+        let over_users = self.users_area.contains(Position::new(mouse.column, mouse.row));
+        match mouse.kind {
+            MouseEventKind::ScrollUp if over_users => self.scroll_users(true, MOUSE_SCROLL_LINES),
+            MouseEventKind::ScrollDown if over_users => self.scroll_users(false, MOUSE_SCROLL_LINES),
+            MouseEventKind::ScrollUp => self.scroll_up(MOUSE_SCROLL_LINES),
+            MouseEventKind::ScrollDown => self.scroll_down(MOUSE_SCROLL_LINES),
+            MouseEventKind::Down(MouseButton::Left) => {
+                if !over_users {
+                    return;
+                }
+
+                // Rows start below the list's top border
+                let row = mouse.row.saturating_sub(self.users_area.y + 1) as usize;
+                let name = self.user_rows.get(row)
+                    .copied()
+                    .flatten()
+                    .and_then(|uid| self.state.lock().unwrap().users.get(&uid).cloned());
+                if let Some(name) = name {
+                    self.input = format!("/whisper {} ", name);
+                    self.character_index = self.input.chars().count();
+                }
+            },
+            _ => (),
         }
     }
 
     // Run this as a separate thread
-    pub fn run(
-        mut self, 
-        mut terminal: DefaultTerminal, 
-        state: Arc<Mutex<ClientState>>,
-    ) -> io::Result<()> {
+    pub fn run(mut self, mut terminal: DefaultTerminal) -> io::Result<()> {
         loop {
-            terminal.draw(|frame| self.draw(frame, &state))?;
-            
-            if event::poll(Duration::from_millis(16))? { 
-                if let Event::Key(key) = event::read()? {
-                    match key.code {
-                        KeyCode::Esc => {
-                            ratatui::restore();
-                            process::exit(0);
+            // Drop tabs whose server went away, quitting with the last one
+            if let Some(index) = self.tabs.iter().position(|tab| tab.state.lock().unwrap().shutdown) {
+                if self.tabs.len() == 1 {
+                    restore_terminal();
+                    println!("Server is shutting down. Goodbye!");
+                    process::exit(0);
+                }
+                let tab = self.remove_tab(index);
+                self.local_message(format!("{} is shutting down, its tab was closed", tab.name));
+            }
+
+            terminal.draw(|frame| self.draw(frame))?;
+            self.update_title();
+
+            if event::poll(Duration::from_millis(16))? {
+                let event = event::read()?;
+                // Any key but Tab settles on the completed name
+                if let Event::Key(key) = &event && !matches!(key.code, KeyCode::Tab | KeyCode::BackTab) {
+                    self.completion = None;
+                }
+                match event {
+                    // Only y quits, any other key goes back to the input
+                    Event::Key(key) if self.confirm_quit => {
+                        self.confirm_quit = false;
+                        if matches!(key.code, KeyCode::Char('y' | 'Y')) {
+                            self.quit("");
+                        }
+                    },
+                    Event::Key(key) => match key.code {
+                        KeyCode::Esc if self.input.is_empty() => self.quit(""),
+                        KeyCode::Esc => self.confirm_quit = true,
+                        // Terminals often can't tell Shift+Enter apart, so Alt+Enter works too
+                        KeyCode::Enter if key.modifiers.intersects(KeyModifiers::SHIFT | KeyModifiers::ALT) => {
+                            self.enter_char('\n');
                         },
                         KeyCode::Enter => self.submit_message(),
+                        KeyCode::Char('u') if key.modifiers.contains(KeyModifiers::CONTROL) => self.clear_input(),
+                        KeyCode::Char('w') if key.modifiers.contains(KeyModifiers::CONTROL) => self.delete_word(),
+                        // While searching, n and N move between matches until typing starts
+                        KeyCode::Char('n') if self.search.is_some() && self.input.is_empty() => self.move_search(true),
+                        KeyCode::Char('N') if self.search.is_some() && self.input.is_empty() => self.move_search(false),
                         KeyCode::Char(to_insert) => self.enter_char(to_insert),
                         KeyCode::Backspace => self.delete_char(),
+                        KeyCode::Tab => self.complete_name(true),
+                        KeyCode::BackTab => self.complete_name(false),
+                        KeyCode::Left if key.modifiers.contains(KeyModifiers::CONTROL) => self.move_cursor_word_left(),
+                        KeyCode::Right if key.modifiers.contains(KeyModifiers::CONTROL) => self.move_cursor_word_right(),
                         KeyCode::Left => self.move_cursor_left(),
                         KeyCode::Right => self.move_cursor_right(),
+                        KeyCode::End if key.modifiers.contains(KeyModifiers::CONTROL) => self.scroll_offset = 0,
+                        KeyCode::Home => self.character_index = 0,
+                        KeyCode::End => self.character_index = self.input.chars().count(),
+                        KeyCode::PageUp if key.modifiers.contains(KeyModifiers::CONTROL) => self.previous_tab(),
+                        KeyCode::PageUp if key.modifiers.contains(KeyModifiers::ALT) => {
+                            self.scroll_users(true, self.users_area.height.saturating_sub(3) as usize);
+                        },
+                        KeyCode::PageDown if key.modifiers.contains(KeyModifiers::ALT) => {
+                            self.scroll_users(false, self.users_area.height.saturating_sub(3) as usize);
+                        },
+                        KeyCode::PageDown if key.modifiers.contains(KeyModifiers::CONTROL) => self.next_tab(),
+                        KeyCode::PageUp => self.scroll_up(self.page_height),
+                        KeyCode::PageDown => self.scroll_down(self.page_height),
+                        KeyCode::Up if key.modifiers.contains(KeyModifiers::CONTROL) => self.scroll_up(1),
+                        KeyCode::Down if key.modifiers.contains(KeyModifiers::CONTROL) => self.scroll_down(1),
+                        KeyCode::Up => self.history_previous(),
+                        KeyCode::Down => self.history_next(),
                         _ => (),
-                    }
+                    },
+                    Event::Mouse(mouse) => self.handle_mouse(mouse),
+                    Event::FocusGained => self.set_focused(true),
+                    Event::FocusLost => self.set_focused(false),
+                    _ => (),
                 }
             }
+
+            self.update_typing();
+            self.update_pending();
+            self.update_receipt();
+            self.update_history();
+        }
+    }
+
+    // Indices of the shown entries containing `query`, newest first
+    fn search_matches(&self, s: &ClientState, query: &str) -> Vec<usize> {
+        s.messages
+            .iter()
+            .enumerate()
+            .rev()
+            .filter(|(_, entry)| self.is_shown(s, entry) && entry_text(entry).to_lowercase().contains(query))
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    // Move the highlight to an older match, or a newer one when `older`
+    // is false, wrapping around at either end
+    fn move_search(&mut self, older: bool) {
+        let Some(query) = &self.search else {
+            return;
+        };
+        let count = self.search_matches(&self.state.lock().unwrap(), query).len();
+        if count > 0 {
+            self.search_match = match older {
+                true => (self.search_match + 1) % count,
+                false => (self.search_match + count - 1) % count,
+            };
+            self.search_jump = true;
         }
     }
 
-    fn draw(&self, frame: &mut Frame, state: &Arc<Mutex<ClientState>>) {
+    // Every tab needs to know, since a mention can arrive on any of them
+    fn set_focused(&self, focused: bool) {
+        for tab in &self.tabs {
+            tab.state.lock().unwrap().focused = focused;
+        }
+    }
+
+    fn is_shown(&self, s: &ClientState, entry: &ChatEntry) -> bool {
+        (self.show_events || !matches!(entry, ChatEntry::System(_))) && !s.is_muted(entry)
+    }
+
+    // Show the number of people online in the terminal's title. Terminals
+    // without title support just ignore the escape sequence.
+    fn update_title(&mut self) {
+        let count = self.state.lock().unwrap().users.len();
+        if self.title_count != Some(count) {
+            self.title_count = Some(count);
+            let _ = execute!(io::stdout(), SetTitle(format!("rust-chat ({} online)", count)));
+        }
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        if too_small(frame, MIN_WIDTH, MIN_HEIGHT) {
+            // Nothing is shown for clicks to land on
+            self.users_area = Rect::default();
+            return;
+        }
+
+        // Tabs are only worth the space once there is more than one
+        let tabs_height = if self.tabs.len() > 1 { 1 } else { 0 };
+        let [tabs_area, main_area] = Layout::vertical([
+            Constraint::Length(tabs_height),
+            Constraint::Min(1),
+        ]).areas(frame.area());
+        if self.tabs.len() > 1 {
+            let titles = self.tabs.iter().map(|tab| tab.name.clone());
+            let tabs = Tabs::new(titles)
+                .select(self.active_tab)
+                .highlight_style(Style::new().reversed());
+            frame.render_widget(tabs, tabs_area);
+        }
+
+        let input_lines = self.input.split('\n').count().min(MAX_INPUT_LINES);
         let vertical = Layout::vertical([
             Constraint::Min(1),
-            Constraint::Length(3),
+            Constraint::Length(input_lines as u16 + 2),
         ]);
         let horizontal = Layout::horizontal([
             Constraint::Percentage(80),
             Constraint::Percentage(20),
         ]);
-        let [content, users_area] = horizontal.areas(frame.area());
+        let [content, users_area] = horizontal.areas(main_area);
         let [message_area, input_area] = vertical.areas(content);
 
-        let s = state.lock().unwrap();
+        let s = self.state.lock().unwrap();
+
+        // Show a banner above the messages once the server is gone
+        let banner = match s.connection_state {
+            ConnectionState::Connected => None,
+            ConnectionState::Reconnecting(attempt) => Some(format!("Reconnecting… (attempt {})", attempt)),
+            ConnectionState::Disconnected => Some(String::from("Disconnected from server")),
+        };
+        let banner_height = if banner.is_some() { 1 } else { 0 };
+        let [banner_area, message_area] = Layout::vertical([
+            Constraint::Length(banner_height),
+            Constraint::Min(1),
+        ]).areas(message_area);
+        if let Some(text) = banner {
+            let banner = Paragraph::new(text).fg(self.colors.error).bold();
+            frame.render_widget(banner, banner_area);
+        }
 
-        // Render messages
-        let messages: Vec<ListItem> = s.messages
+        // Show who else is typing between the messages and the input box
+        let mut typing: Vec<&str> = s.typing
             .iter()
-            .enumerate()
-            .map(|(_, message)| {
-                let start = message.chars().nth(0).unwrap();
-                let item;
-                if start == '(' {
-                    item = Line::from(message.clone());
+            .filter(|(uid, since)| **uid != s.uid && !s.muted.contains(uid) && since.elapsed() < TYPING_TIMEOUT)
+            .filter_map(|(uid, _)| s.users.get(uid).map(String::as_str))
+            .collect();
+        typing.sort();
+        let typing_height = if typing.is_empty() { 0 } else { 1 };
+        let [message_area, typing_area] = Layout::vertical([
+            Constraint::Min(1),
+            Constraint::Length(typing_height),
+        ]).areas(message_area);
+        if !typing.is_empty() {
+            let text = Paragraph::new(format!(" {}", typing_line(&typing))).fg(self.colors.system).italic();
+            frame.render_widget(text, typing_area);
+        }
+
+        // Work out which rows fit in the pane. Long messages wrap, so
+        // scrolling counts display rows rather than messages.
+        let height = message_area.height.saturating_sub(2) as usize;
+        let width = message_area.width.saturating_sub(2) as usize;
+        let total = s.messages.len();
+        // Older history goes above what was drawn and the cap drops entries
+        // from the top. Bottom-up scrolling leaves the view in place, but
+        // where newly added messages start shifts.
+        self.message_count += s.prepended.saturating_sub(self.prepended);
+        self.message_count = self.message_count.saturating_sub(s.dropped.saturating_sub(self.dropped));
+        (self.prepended, self.dropped) = (s.prepended, s.dropped);
+        if self.scroll_offset > 0 && total > self.message_count {
+            // Keep the view where it is while the user reads older messages
+            let added: usize = s.messages[self.message_count..]
+                .iter()
+                .filter(|entry| self.is_shown(&s, entry))
+                .map(|entry| entry_rows(entry, &s, &self.colors, self.right_align_own, width).len())
+                .sum();
+            self.scroll_offset += added;
+            self.unread += s.messages[self.message_count..]
+                .iter()
+                .filter(|entry| match entry {
+                    ChatEntry::Message { sender_id, .. } | ChatEntry::Whisper { sender_id, .. } => {
+                        *sender_id != s.uid && !s.muted.contains(sender_id)
+                    },
+                    _ => false,
+                })
+                .count();
+        }
+        self.message_count = total;
+        self.page_height = height;
+
+        // Matches of the current search, and the entry that is highlighted
+        let matches = match &self.search {
+            Some(query) => self.search_matches(&s, query),
+            None => Vec::new(),
+        };
+        self.search_match = self.search_match.min(matches.len().saturating_sub(1));
+        let current = matches.get(self.search_match).copied();
+
+        // Bring the highlighted match to the bottom of the view
+        if self.search_jump && let Some(current) = current {
+            self.scroll_offset = s.messages[current + 1..]
+                .iter()
+                .filter(|entry| self.is_shown(&s, entry))
+                .map(|entry| entry_rows(entry, &s, &self.colors, self.right_align_own, width).len())
+                .sum();
+        }
+        self.search_jump = false;
+
+        // Wrap messages from the newest back until the view is filled,
+        // noting which entry each row belongs to
+        let wanted = self.scroll_offset + height;
+        let seen = seen_by(&s);
+        let mut rows: Vec<Line> = Vec::new();
+        let mut row_entries: Vec<usize> = Vec::new();
+        let mut all_wrapped = true;
+        for (index, entry) in s.messages.iter().enumerate().rev().filter(|(_, entry)| self.is_shown(&s, entry)) {
+            if rows.len() >= wanted {
+                all_wrapped = false;
+                break;
+            }
+            let mut entry_rows = entry_rows(entry, &s, &self.colors, self.right_align_own, width);
+            if let Some((seen_index, text)) = &seen && *seen_index == index {
+                if self.right_align_own {
+                    let line = Line::from(text.clone()).fg(self.colors.timestamp).italic();
+                    entry_rows.extend(wrap::wrap_right(line, width));
                 }
                 else {
-                    item = Line::from(message.clone()).red();
+                    let line = Line::from(format!("  {}", text)).fg(self.colors.timestamp).italic();
+                    entry_rows.extend(wrap::wrap_line(line, width, 2));
                 }
-                ListItem::new(item)
-            })
+            }
+            if let Some(query) = &self.search && matches.contains(&index) {
+                let style = if Some(index) == current {
+                    Style::new().black().on_yellow()
+                }
+                else {
+                    Style::new().reversed()
+                };
+                entry_rows = entry_rows.into_iter().map(|row| highlight(row, query, style)).collect();
+            }
+            entry_rows.reverse();
+            row_entries.extend(std::iter::repeat_n(index, entry_rows.len()));
+            rows.extend(entry_rows);
+        }
+        self.scroll_offset = self.scroll_offset.min(rows.len().saturating_sub(height));
+        let start = self.scroll_offset;
+        let end = (start + height).min(rows.len());
+        if start == 0 {
+            self.unread = 0;
+        }
+        self.at_top = all_wrapped && end == rows.len();
+
+        // Rows run from the bottom of the view up. Our own messages need
+        // no receipt.
+        self.newest_visible = row_entries[start..end].iter().find_map(|index| match &s.messages[*index] {
+            ChatEntry::Message { uid, sender_id, .. } if *uid != 0 && *sender_id != s.uid => Some(*uid),
+            _ => None,
+        });
+
+        // Render messages, oldest at the top
+        let messages: Vec<ListItem> = rows[start..end]
+            .iter()
+            .rev()
+            .cloned()
+            .map(ListItem::new)
             .collect();
-        let messages = List::new(messages).block(Block::bordered().title("Messages"));
+        let title = match &self.search {
+            Some(query) if !matches.is_empty() => format!(
+                "Messages - #{} - \"{}\" {} of {} (n/N to move, /search to clear)",
+                s.room,
+                query,
+                self.search_match + 1,
+                matches.len(),
+            ),
+            _ => format!("Messages - #{}", s.room),
+        };
+        // Connection status in the corner of the message pane
+        let status = match s.connection_state {
+            ConnectionState::Connected => Span::raw("● Connected").green(),
+            ConnectionState::Reconnecting(_) => Span::raw("● Reconnecting").yellow(),
+            ConnectionState::Disconnected => Span::raw("● Disconnected").fg(self.colors.error),
+        };
+        let mut block = Block::bordered()
+            .title(title)
+            .title(Line::from(status).right_aligned())
+            .border_style(self.colors.border);
+        if self.unread > 0 {
+            let plural = if self.unread == 1 { "" } else { "s" };
+            let notice = format!(" ▼ {} new message{} (Ctrl+End) ", self.unread, plural);
+            block = block.title_bottom(Line::from(notice).bold().centered());
+        }
+        let messages = List::new(messages).block(block);
         frame.render_widget(messages, message_area);
 
-        // Render Input Box
+        // Render Input Box, scrolled to keep the cursor's line in view
+        let before_cursor: String = self.input.chars().take(self.character_index).collect();
+        let cursor_row = before_cursor.matches('\n').count();
+        let cursor_column = before_cursor.rsplit('\n').next().unwrap_or_default().width();
+        let input_scroll = cursor_row.saturating_sub(input_lines - 1);
         let input = Paragraph::new(self.input.as_str())
             .style(Style::default())
-            .block(Block::bordered().title("Input"));
+            .scroll((input_scroll as u16, 0))
+            .block(Block::bordered().title("Input").border_style(self.colors.border));
         frame.render_widget(input, input_area);
-        frame.set_cursor_position((
-            input_area.x + self.character_index as u16 + 1,
-            input_area.y + 1,
-        ));
+        set_cursor(
+            frame,
+            input_area.x + cursor_column as u16 + 1,
+            input_area.y + (cursor_row - input_scroll) as u16 + 1,
+        );
+
+        // Render user list, sorted so it doesn't shuffle between draws.
+        // Rows at either end say how many users are scrolled past.
+        let order = s.sorted_users();
+        let height = users_area.height.saturating_sub(2) as usize;
+        let max_scroll = if order.len() > height { (order.len() + 1).saturating_sub(height) } else { 0 };
+        self.users_scroll = self.users_scroll.min(max_scroll);
+        let above = self.users_scroll.min(order.len());
+        let room = height.saturating_sub(usize::from(above > 0));
+        let rest = order.len() - above;
+        let shown = if rest > room { room.saturating_sub(1) } else { rest };
+        let below = rest - shown;
 
-        // Render user list
         let mut users: Vec<ListItem> = vec![];
-        for (_, name) in s.users.iter() {
-            users.push(ListItem::new(Line::from(name.clone())));
+        self.users_area = users_area;
+        self.user_rows.clear();
+        if above > 0 {
+            users.push(ListItem::new(Line::from(format!("↑ {} more", above)).fg(self.colors.system)));
+            self.user_rows.push(None);
         }
-        let users = List::new(users).block(Block::bordered().title("Users"));
+        for (uid, name) in order.iter().skip(above).take(shown) {
+            self.user_rows.push(Some(*uid));
+            let line = if s.away.contains_key(uid) {
+                Line::from(format!("{} (away)", name)).fg(user_color(&s, *uid)).dim()
+            }
+            else if let Some(idle) = s.idle_for(*uid) {
+                Line::from(format!("{} (idle {})", name, net::format_idle(idle))).fg(user_color(&s, *uid)).dim()
+            }
+            else {
+                Line::from(name.to_string()).fg(user_color(&s, *uid))
+            };
+            users.push(ListItem::new(line));
+        }
+        if below > 0 {
+            users.push(ListItem::new(Line::from(format!("+{} more", below)).fg(self.colors.system)));
+            self.user_rows.push(None);
+        }
+        let title = format!("Users ({})", s.users.len());
+        let users = List::new(users).block(Block::bordered().title(title).border_style(self.colors.border));
         frame.render_widget(users, users_area);
+
+        // Names Tab cycles through, just above the input
+        if let Some(completion) = self.completion.as_ref().filter(|completion| completion.names.len() > 1) {
+            let height = (completion.names.len().min(MAX_COMPLETIONS_SHOWN) as u16 + 2).min(input_area.y);
+            let width = completion.names.iter().map(|name| name.width()).max().unwrap_or_default() as u16 + 2;
+            let area = Rect {
+                x: input_area.x,
+                y: input_area.y - height,
+                width: width.min(input_area.width),
+                height,
+            };
+            let names = List::new(completion.names.iter().map(String::as_str))
+                .highlight_style(Style::new().reversed())
+                .block(Block::bordered().border_style(self.colors.border));
+            frame.render_widget(Clear, area);
+            frame.render_stateful_widget(names, area, &mut ListState::default().with_selected(completion.selected));
+        }
+
+        if self.confirm_quit {
+            let [prompt_area] = Layout::vertical([Constraint::Length(3)]).flex(Flex::Center).areas(frame.area());
+            let [prompt_area] = Layout::horizontal([Constraint::Length(36)]).flex(Flex::Center).areas(prompt_area);
+            let prompt = Paragraph::new("Discard message and quit? (y/n)")
+                .centered()
+                .block(Block::bordered().border_style(self.colors.error));
+            frame.render_widget(Clear, prompt_area);
+            frame.render_widget(prompt, prompt_area);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, BufWriter};
+    use ratatui::{Terminal, backend::TestBackend, layout::Alignment};
+    use crate::core::net::PacketWriter;
+    use super::*;
+
+    fn tab(name: &str) -> Tab {
+        let writer: Box<dyn io::Write + Send> = Box::new(io::sink());
+        Tab {
+            name: name.to_string(),
+            stream: Arc::new(Mutex::new(PacketWriter::new(BufWriter::new(writer)))),
+            state: Arc::new(Mutex::new(ClientState::default())),
+        }
+    }
+
+    fn chat() -> Chat {
+        let tab = tab("");
+        let settings = TabSettings {
+            download_dir: PathBuf::new(),
+            notifications: Notifications::Off,
+            max_messages: None,
+        };
+        Chat::new(tab, Colors::default(), false, None, settings, HashMap::new())
+    }
+
+    #[test]
+    fn insert_into_middle_of_multibyte_input() {
+        let mut chat = chat();
+        for c in "a🎉é".chars() {
+            chat.enter_char(c);
+        }
+        chat.move_cursor_left();
+        chat.enter_char('x');
+
+        assert_eq!(chat.input, "a🎉xé");
+        assert_eq!(chat.character_index, 3);
+    }
+
+    #[test]
+    fn own_messages_wrap_against_the_right_edge() {
+        let s = ClientState {
+            uid: 1,
+            ..Default::default()
+        };
+        let own = ChatEntry::from_message(Message {
+            uid: 5,
+            sender_id: 1,
+            sender_name: String::from("alice"),
+            message: String::from("a long message that has to wrap onto more than one row"),
+            ..Default::default()
+        });
+
+        let rows = entry_rows(&own, &s, &Colors::default(), true, 20);
+        assert!(rows.len() > 1);
+        for row in &rows {
+            let text = row.to_string();
+            assert_eq!(row.alignment, Some(Alignment::Right));
+            assert!(row.width() <= 20, "{:?} is too wide", text);
+            assert_eq!(text, text.trim(), "{:?} isn't flush with the edge", text);
+        }
+
+        // Everyone else's messages stay on the left
+        let other = ChatEntry::from_message(Message {
+            sender_id: 2,
+            ..Default::default()
+        });
+        assert!(entry_rows(&other, &s, &Colors::default(), true, 20).iter().all(|row| row.alignment.is_none()));
+    }
+
+    #[test]
+    fn user_list_is_sorted_and_stable_across_renders() {
+        let mut chat = chat();
+        let mut terminal = Terminal::new(TestBackend::new(100, 12)).unwrap();
+        let names = ["dave", "Alice", "carol", "bob", "Erin"];
+
+        let mut renders = Vec::new();
+        for round in 0..names.len() {
+            // A fresh map, filled in a different order, iterates differently
+            chat.state.lock().unwrap().users = (0..names.len())
+                .map(|index| (round + index) % names.len())
+                .map(|index| (index as u32 + 1, names[index].to_string()))
+                .collect();
+            terminal.draw(|frame| chat.draw(frame)).unwrap();
+
+            let area = chat.users_area;
+            let buffer = terminal.backend().buffer();
+            let rows: Vec<String> = (area.top() + 1..area.bottom() - 1)
+                .map(|y| {
+                    (area.left() + 1..area.right() - 1)
+                        .map(|x| buffer[(x, y)].symbol())
+                        .collect::<String>()
+                        .trim()
+                        .to_string()
+                })
+                .filter(|row| !row.is_empty())
+                .collect();
+            renders.push(rows);
+        }
+
+        assert_eq!(renders[0], ["Alice", "bob", "carol", "dave", "Erin"]);
+        assert!(renders.iter().all(|rows| *rows == renders[0]));
+    }
+
+    #[test]
+    fn small_terminal_gets_a_notice_and_long_input_keeps_the_cursor_inside() {
+        let mut chat = chat();
+        let mut terminal = Terminal::new(TestBackend::new(30, 6)).unwrap();
+        terminal.draw(|frame| chat.draw(frame)).unwrap();
+        let buffer = terminal.backend().buffer();
+        let screen: String = buffer.content().iter().map(|cell| cell.symbol()).collect();
+        assert!(screen.contains("Terminal too small"));
+
+        let mut terminal = Terminal::new(TestBackend::new(MIN_WIDTH, MIN_HEIGHT)).unwrap();
+        for c in "x".repeat(100).chars() {
+            chat.enter_char(c);
+        }
+        terminal.draw(|frame| chat.draw(frame)).unwrap();
+        let cursor = terminal.get_cursor_position().unwrap();
+        assert!(cursor.x < MIN_WIDTH && cursor.y < MIN_HEIGHT);
+    }
+
+    #[test]
+    fn quit_prompt_is_drawn_over_the_chat() {
+        let mut chat = chat();
+        let mut terminal = Terminal::new(TestBackend::new(60, 12)).unwrap();
+        let screen = |terminal: &Terminal<TestBackend>| -> String {
+            terminal.backend().buffer().content().iter().map(|cell| cell.symbol()).collect()
+        };
+        terminal.draw(|frame| chat.draw(frame)).unwrap();
+        assert!(!screen(&terminal).contains("Discard message and quit?"));
+
+        chat.confirm_quit = true;
+        terminal.draw(|frame| chat.draw(frame)).unwrap();
+        assert!(screen(&terminal).contains("Discard message and quit? (y/n)"));
+    }
+
+    #[test]
+    fn tab_completes_usernames() {
+        let mut chat = chat();
+        chat.state.lock().unwrap().users = [(1, "alice"), (2, "Alina"), (3, "bob")]
+            .into_iter()
+            .map(|(uid, name)| (uid, name.to_string()))
+            .collect();
+        let typed = |chat: &mut Chat, text: &str| {
+            chat.clear_input();
+            chat.completion = None;
+            for c in text.chars() {
+                chat.enter_char(c);
+            }
+        };
+
+        typed(&mut chat, "/w b");
+        chat.complete_name(true);
+        assert_eq!(chat.input, "/w bob ");
+
+        // The shared part first, then each match in turn
+        typed(&mut chat, "hi @AL");
+        chat.complete_name(true);
+        assert_eq!(chat.input, "hi @ali");
+        chat.complete_name(true);
+        assert_eq!(chat.input, "hi @alice ");
+        chat.complete_name(true);
+        assert_eq!(chat.input, "hi @Alina ");
+        chat.complete_name(false);
+        assert_eq!(chat.input, "hi @alice ");
+
+        // At the start of a line it's a mention
+        typed(&mut chat, "bo");
+        chat.complete_name(true);
+        assert_eq!(chat.input, "@bob ");
+    }
+
+    // Keeps everything written to it for the test to read back
+    struct Capture(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn raw_and_doubled_slash_send_the_literal_text() {
+        let mut chat = chat();
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let writer: Box<dyn io::Write + Send> = Box::new(Capture(written.clone()));
+        chat.stream = Arc::new(Mutex::new(PacketWriter::new(BufWriter::new(writer))));
+        for input in ["/raw /hello", "//hello", "//:smile: is a shortcode", ":smile:"] {
+            chat.set_input(input.to_string());
+            chat.submit_message();
+        }
+
+        let mut data = io::Cursor::new(written.lock().unwrap().clone());
+        let sent: Vec<String> = std::iter::from_fn(|| net::read_packet(&mut data).ok())
+            .map(|packet| packet.contents)
+            .collect();
+        assert_eq!(sent, ["/hello", "/hello", "/:smile: is a shortcode", "😄"]);
+    }
+
+    #[test]
+    fn local_commands_work_while_reconnecting() {
+        let mut chat = chat();
+        {
+            let mut s = chat.state.lock().unwrap();
+            s.connection_state = ConnectionState::Reconnecting(1);
+            s.messages.push(ChatEntry::Notice(String::from("old")));
+        }
+        let submit = |chat: &mut Chat, text: &str| {
+            chat.set_input(text.to_string());
+            chat.submit_message();
+        };
+
+        // Messages wait in the input box until we are back
+        submit(&mut chat, "hello");
+        assert_eq!(chat.input, "hello");
+        assert!(matches!(
+            chat.state.lock().unwrap().messages.last(),
+            Some(ChatEntry::Notice(text)) if text == "Not connected, message not sent"
+        ));
+
+        submit(&mut chat, "/clear");
+        assert!(chat.input.is_empty());
+        assert!(chat.state.lock().unwrap().messages.is_empty());
+    }
+
+    #[test]
+    fn commands_find_names_with_spaces() {
+        let mut chat = chat();
+        chat.state.lock().unwrap().users = [(1, "Jane"), (2, "Jane Doe"), (3, "bob")]
+            .into_iter()
+            .map(|(uid, name)| (uid, name.to_string()))
+            .collect();
+
+        let packet = chat.parse_command(String::from("/w Jane Doe  hi there")).unwrap();
+        assert_eq!((packet.target, packet.contents.as_str()), (2, "hi there"));
+        let packet = chat.parse_command(String::from("/whisper Jane Doesn't matter")).unwrap();
+        assert_eq!((packet.target, packet.contents.as_str()), (1, "Doesn't matter"));
+        let packet = chat.parse_command(String::from("/kick Jane Doe")).unwrap();
+        assert_eq!((packet.target, packet.contents.as_str()), (2, ""));
+
+        // A name with nothing to say is only a usage error
+        assert!(chat.parse_command(String::from("/w Jane Doe")).is_none());
+        assert!(chat.parse_command(String::from("/w Janet hi")).is_none());
+    }
+
+    #[test]
+    fn removing_an_earlier_tab_keeps_the_one_shown() {
+        let mut chat = chat();
+        chat.tabs = vec![tab("a"), tab("b"), tab("c")];
+        chat.switch_tab(2);
+
+        chat.remove_tab(0);
+        assert_eq!(chat.active_tab, 1);
+        assert_eq!(chat.tabs[chat.active_tab].name, "c");
+        assert!(Arc::ptr_eq(&chat.state, &chat.tabs[1].state));
+
+        // Losing the one shown moves to its neighbour
+        chat.remove_tab(1);
+        assert_eq!(chat.active_tab, 0);
+        assert!(Arc::ptr_eq(&chat.state, &chat.tabs[0].state));
+    }
+
+    #[test]
+    fn connect_passwords_are_left_out_of_the_input_history() {
+        let mut chat = chat();
+        chat.set_input(String::from("/connect example.com:8080 hunter2"));
+        chat.remember_input();
+        chat.set_input(String::from("/connect example.com:9090"));
+        chat.remember_input();
+        assert_eq!(chat.history, ["/connect example.com:8080", "/connect example.com:9090"]);
+    }
+
+    #[test]
+    fn muted_users_are_hidden_until_unmuted() {
+        let mut chat = chat();
+        {
+            let mut s = chat.state.lock().unwrap();
+            s.users.insert(2, String::from("bob"));
+            s.messages.push(ChatEntry::Message {
+                uid: 1,
+                sender_id: 2,
+                sender_name: String::from("bob"),
+                timestamp: 0,
+                text: String::from("spam spam spam"),
+                edited: false,
+                action: false,
+                reply_to: None,
+                delivery: Delivery::Received,
+            });
+        }
+        let mut terminal = Terminal::new(TestBackend::new(80, 12)).unwrap();
+        let mut shows_spam = |chat: &mut Chat| {
+            terminal.draw(|frame| chat.draw(frame)).unwrap();
+            let screen: String = terminal.backend().buffer().content().iter().map(|cell| cell.symbol()).collect();
+            screen.contains("spam spam spam")
+        };
+
+        assert!(shows_spam(&mut chat));
+        chat.parse_command(String::from("/mute bob"));
+        assert!(!shows_spam(&mut chat));
+        chat.parse_command(String::from("/unmute bob"));
+        assert!(shows_spam(&mut chat));
     }
 }
@@ -0,0 +1,115 @@
+use ratatui::{
+    style::Style,
+    text::{Line, Span},
+};
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+// Break a line into rows no wider than `width` columns, at spaces where
+// possible and always at newlines. Continuation rows are indented by
+// `indent` columns unless that would leave too little room for the text.
+pub fn wrap_line(line: Line<'static>, width: usize, indent: usize) -> Vec<Line<'static>> {
+    let width = width.max(1);
+    let mut wrapper = Wrapper {
+        width,
+        indent: if indent < width / 2 { indent } else { 0 },
+        style: line.style,
+        rows: Vec::new(),
+        row: Vec::new(),
+        row_width: 0,
+    };
+
+    for span in &line.spans {
+        for (index, part) in span.content.split('\n').enumerate() {
+            if index > 0 {
+                wrapper.break_row();
+            }
+            for word in part.split_inclusive(' ') {
+                wrapper.push_word(word, span.style);
+            }
+        }
+    }
+
+    wrapper.rows.push(Line::from(wrapper.row).style(wrapper.style));
+    wrapper.rows
+}
+
+// Wrap a line and push each row against the right edge. Continuation rows
+// aren't indented, and the spaces rows were broken at are dropped so the
+// text ends flush with the edge.
+pub fn wrap_right(line: Line<'static>, width: usize) -> Vec<Line<'static>> {
+    wrap_line(line, width, 0)
+        .into_iter()
+        .map(|mut row| {
+            while let Some(last) = row.spans.last_mut() {
+                let trimmed = last.content.trim_end().len();
+                if trimmed > 0 {
+                    last.content.to_mut().truncate(trimmed);
+                    break;
+                }
+                row.spans.pop();
+            }
+            row.right_aligned()
+        })
+        .collect()
+}
+
+struct Wrapper {
+    width: usize,
+    indent: usize,
+    style: Style,
+
+    rows: Vec<Line<'static>>,
+    row: Vec<Span<'static>>,
+    row_width: usize,
+}
+
+impl Wrapper {
+    fn break_row(&mut self) {
+        let indent = Span::raw(" ".repeat(self.indent));
+        let row = std::mem::replace(&mut self.row, vec![indent]);
+        self.rows.push(Line::from(row).style(self.style));
+        self.row_width = self.indent;
+    }
+
+    fn push_word(&mut self, word: &str, style: Style) {
+        // Trailing spaces are allowed to hang past the edge
+        let visible = word.trim_end().width();
+        if self.row_width + visible > self.width && self.row_width > self.indent {
+            self.break_row();
+        }
+
+        // A word too long for a row of its own is split wherever it has to be
+        let mut rest = word;
+        while self.row_width + rest.trim_end().width() > self.width {
+            let room = self.width.saturating_sub(self.row_width);
+            let mut split = 0;
+            let mut used = 0;
+            for (index, c) in rest.char_indices() {
+                let c_width = c.width().unwrap_or(0);
+                if used + c_width > room {
+                    break;
+                }
+                used += c_width;
+                split = index + c.len_utf8();
+            }
+
+            // Always make progress, even if a single character doesn't fit
+            if split == 0 {
+                if self.row_width > self.indent {
+                    self.break_row();
+                    continue;
+                }
+                split = rest.chars().next().map_or(rest.len(), char::len_utf8);
+            }
+
+            self.row.push(Span::styled(rest[..split].to_string(), style));
+            self.break_row();
+            rest = &rest[split..];
+        }
+
+        if !rest.is_empty() {
+            self.row.push(Span::styled(rest.to_string(), style));
+            self.row_width += rest.width();
+        }
+    }
+}
@@ -2,12 +2,19 @@ use std::io;
 
 pub mod core;
 use crate::core::{
-    ui::App,
+    config::Config,
+    headless,
+    ui::{self, App},
 };
 
 fn main() -> io::Result<()> {
-    let app = App::new();
-    let terminal = ratatui::init();
+    let config = Config::from_args()?;
+    if config.headless {
+        return headless::run(config);
+    }
+
+    let app = App::new(config);
+    let terminal = ui::init_terminal()?;
 
     app.run(terminal)?;
     
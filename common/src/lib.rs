@@ -0,0 +1,477 @@
+// Wire format shared by the client and server. Both sides frame packets
+// the same way; only how the bytes reach the socket differs.
+use std::fmt;
+use std::io::{self, Read, Write};
+use flate2::{Compression, read::DeflateDecoder, write::DeflateEncoder};
+use serde::{Serialize, Deserialize};
+
+// Largest frame we are willing to allocate for
+pub const MAX_PACKET_SIZE: usize = 1 << 20;
+
+// Version of the packet format, exchanged during the handshake. Clients
+// and servers only talk to peers on the same version, so bump it whenever
+// a change would leave older peers unable to read our packets.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+// The top byte of a frame's length prefix holds flags and the rest the
+// length. Lengths never reach the flags, so frames from peers that don't
+// know about them look the same as before.
+const LENGTH_MASK: u32 = 0x00FF_FFFF;
+const FLAG_DEFLATE: u32 = 1 << 24;
+const FLAG_MSGPACK: u32 = 1 << 25;
+const _: () = assert!(MAX_PACKET_SIZE <= LENGTH_MASK as usize);
+
+// Frames bigger than this are compressed if the peer accepts it
+const COMPRESSION_THRESHOLD: usize = 1024;
+
+// Offered by the server in IDAssign and accepted by the client in its
+// first UsernameChange. Only then are compressed frames sent.
+pub const FEATURE_DEFLATE: &str = "deflate";
+
+// Offered in IDAssign by servers set up for it and accepted the same way.
+// Frames are then MessagePack instead of JSON.
+pub const FEATURE_MSGPACK: &str = "msgpack";
+
+// Read receipts are only kept for this many of the most recent message ids
+pub const RECEIPT_WINDOW: u32 = 50;
+
+// Messages sent when joining a room, and asked for by each HistoryRequest
+pub const HISTORY_PAGE: u32 = 50;
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PacketType {
+    #[default]
+    None,
+    IDAssign,
+    UserConnected,
+    UserDisconnected,
+    UserList,
+    UsernameChange,
+    NewMessage,
+    MessageHistory,
+    JoinRoom,
+    PrivateMessage,
+    Error,
+    Ping,
+    Pong,
+    ServerShutdown,
+    EditMessage,
+    DeleteMessage,
+    Typing,
+    StoppedTyping,
+    StatusChange,
+    RateLimited,
+    ServerFull,
+    Auth,
+    IdleKick,
+    Ack,
+    Kick,
+    FileStart,
+    FileChunk,
+    FileEnd,
+    VersionMismatch,
+    ReadReceipt,
+    DiceRoll,
+    HistoryRequest,
+    UserActive,
+    Stats,
+    ColorChange,
+    Grep,
+    Motd,
+    WhoisRequest,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Packet {
+    pub packet_type: PacketType,
+
+    pub user_id: u32,
+    pub contents: String,
+
+    // Room a chat message belongs to, or the room to join
+    #[serde(default)]
+    pub room: String,
+
+    // Recipient of a private message
+    #[serde(default)]
+    pub target: u32,
+
+    // Full stored message, attached to new and replayed chat messages
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<Message>,
+
+    // Chosen by the sender of a chat message and echoed in the server's
+    // Ack, so the sender knows which message was stored
+    #[serde(default)]
+    pub nonce: u32,
+
+    // Describes the file a transfer packet belongs to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<FileInfo>,
+
+    // Optional protocol features the sender supports, exchanged during
+    // the handshake
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub features: Vec<String>,
+
+    // PROTOCOL_VERSION of the sender, set in IDAssign and the client's
+    // first UsernameChange. Peers from before versioning send 0.
+    #[serde(default)]
+    pub version: u32,
+
+    // Everyone who has seen the message a ReadReceipt is about
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub readers: Vec<u32>,
+
+    // Most messages a HistoryRequest wants back, or how many of the
+    // messages matching a Grep are sent. A HistoryRequest answer holding
+    // fewer than this reached the start of the room.
+    #[serde(default)]
+    pub limit: u32,
+
+    // Answer to a HistoryRequest, oldest first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub history: Vec<Message>,
+
+    // Seconds since a user in a UserList last sent a message
+    #[serde(default)]
+    pub idle: u64,
+
+    // Messages the user a Stats or WhoisRequest answer is about has sent
+    // this session, or how many messages matched a Grep in all
+    #[serde(default)]
+    pub count: u32,
+
+    // Address the user a Stats or WhoisRequest answer is about connected
+    // from. Only filled in for admins.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub address: String,
+
+    // Unix time the user a WhoisRequest answer is about connected
+    #[serde(default)]
+    pub connected_at: u64,
+
+    // Names the user a WhoisRequest answer is about went by before, most
+    // recent last
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub previous_names: Vec<String>,
+}
+
+// Files are sent to one user as a FileStart, the file's contents split
+// into base64 FileChunks, then a FileEnd, all with the same transfer id
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileInfo {
+    pub transfer_id: u32,
+
+    // Only set on FileStart
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub size: u64,
+
+    // Position of a chunk in the transfer, starting at 0. FileEnd carries
+    // the number of chunks sent.
+    #[serde(default)]
+    pub sequence: u32,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Message {
+    pub uid: u32,
+    pub sender_id: u32,
+    pub sender_name: String,
+    pub message: String,
+
+    // Unix time in seconds
+    pub timestamp: u64,
+
+    // Set once the sender has changed the text
+    #[serde(default)]
+    pub edited: bool,
+
+    // Sent with /me and shown as `* alice waves`
+    #[serde(default)]
+    pub action: bool,
+
+    // Id of the message this one answers, sent with /reply
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<u32>,
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.sender_id, self.message)
+    }
+}
+
+impl Packet {
+    // Notice sent to a single client when one of its requests fails
+    pub fn error(contents: impl Into<String>) -> Self {
+        Packet {
+            packet_type: PacketType::Error,
+            contents: contents.into(),
+            ..Default::default()
+        }
+    }
+}
+
+// Write half of a connection, along with whether the peer has agreed to
+// receive compressed and MessagePack frames
+pub struct PacketWriter<W> {
+    inner: W,
+    pub compress: bool,
+    pub msgpack: bool,
+}
+
+impl<W> PacketWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self { inner, compress: false, msgpack: false }
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    // Every packet is sent as a 4-byte big-endian length prefix followed by
+    // that many bytes of JSON or MessagePack, deflated if the prefix says
+    // so. Returns the prefix and the bytes to send after it.
+    pub fn encode(&self, packet: &Packet) -> io::Result<(u32, Vec<u8>)> {
+        encode(packet, self.compress, self.msgpack)
+    }
+}
+
+// Serialize a packet, compressing it when allowed and worth it
+fn encode(packet: &Packet, compress: bool, msgpack: bool) -> io::Result<(u32, Vec<u8>)> {
+    // Fields are written by name, since skipped ones would shift the rest
+    // out of place otherwise
+    let (flags, data) = if msgpack {
+        (FLAG_MSGPACK, rmp_serde::to_vec_named(packet).map_err(io::Error::other)?)
+    }
+    else {
+        (0, serde_json::to_vec(packet)?)
+    };
+    // The peer inflates frames before checking their size, so the limit
+    // applies to the uncompressed bytes. Past it the length would also
+    // run into the flags.
+    if data.len() > MAX_PACKET_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Packet of {} bytes exceeds the maximum size", data.len()),
+        ));
+    }
+    if compress && data.len() > COMPRESSION_THRESHOLD {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
+        encoder.write_all(&data)?;
+        let compressed = encoder.finish()?;
+        if compressed.len() < data.len() {
+            return Ok((flags | FLAG_DEFLATE | compressed.len() as u32, compressed));
+        }
+    }
+    Ok((flags | data.len() as u32, data))
+}
+
+// How many bytes follow a length prefix, rejecting frames we can't read
+pub fn frame_length(prefix: u32) -> io::Result<usize> {
+    if prefix & !(LENGTH_MASK | FLAG_DEFLATE | FLAG_MSGPACK) != 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Packet has unknown flags set"));
+    }
+    let length = (prefix & LENGTH_MASK) as usize;
+    if length > MAX_PACKET_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Packet of {} bytes exceeds the maximum size", length),
+        ));
+    }
+    Ok(length)
+}
+
+// Turn the bytes of a frame back into a packet
+pub fn decode(prefix: u32, data: &[u8]) -> io::Result<Packet> {
+    let inflated;
+    let data = if prefix & FLAG_DEFLATE != 0 {
+        inflated = inflate(data)?;
+        &inflated
+    }
+    else {
+        data
+    };
+    // A frame cut short is a bad frame, not the peer hanging up
+    if prefix & FLAG_MSGPACK != 0 {
+        return rmp_serde::from_slice(data).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error));
+    }
+    serde_json::from_slice(data).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+}
+
+// Decompress a frame, refusing to grow it past the maximum packet size
+fn inflate(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut inflated = Vec::new();
+    DeflateDecoder::new(data)
+        .take(MAX_PACKET_SIZE as u64 + 1)
+        .read_to_end(&mut inflated)?;
+    if inflated.len() > MAX_PACKET_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Compressed packet exceeds the maximum size"));
+    }
+    Ok(inflated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL_TYPES: &[PacketType] = &[
+        PacketType::None,
+        PacketType::IDAssign,
+        PacketType::UserConnected,
+        PacketType::UserDisconnected,
+        PacketType::UserList,
+        PacketType::UsernameChange,
+        PacketType::NewMessage,
+        PacketType::MessageHistory,
+        PacketType::JoinRoom,
+        PacketType::PrivateMessage,
+        PacketType::Error,
+        PacketType::Ping,
+        PacketType::Pong,
+        PacketType::ServerShutdown,
+        PacketType::EditMessage,
+        PacketType::DeleteMessage,
+        PacketType::Typing,
+        PacketType::StoppedTyping,
+        PacketType::StatusChange,
+        PacketType::RateLimited,
+        PacketType::ServerFull,
+        PacketType::Auth,
+        PacketType::IdleKick,
+        PacketType::Ack,
+        PacketType::Kick,
+        PacketType::FileStart,
+        PacketType::FileChunk,
+        PacketType::FileEnd,
+        PacketType::VersionMismatch,
+        PacketType::ReadReceipt,
+        PacketType::DiceRoll,
+        PacketType::HistoryRequest,
+        PacketType::UserActive,
+        PacketType::Stats,
+        PacketType::ColorChange,
+        PacketType::Grep,
+        PacketType::Motd,
+        PacketType::WhoisRequest,
+    ];
+
+    // Both formats have to give back the same packet
+    fn round_trip(packet: &Packet) -> Packet {
+        let (prefix, data) = encode(packet, false, false).unwrap();
+        let packet = decode(prefix, &data).unwrap();
+        let (prefix, data) = encode(&packet, false, true).unwrap();
+        assert_eq!(decode(prefix, &data).unwrap(), packet);
+        packet
+    }
+
+    #[test]
+    fn every_packet_type_round_trips() {
+        for packet_type in ALL_TYPES {
+            let packet = Packet {
+                packet_type: packet_type.clone(),
+                user_id: 42,
+                contents: String::from("hello"),
+                ..Default::default()
+            };
+            assert_eq!(round_trip(&packet), packet);
+        }
+    }
+
+    #[test]
+    fn default_packet_round_trips() {
+        let packet = Packet::default();
+        assert_eq!(packet.packet_type, PacketType::None);
+        assert_eq!(round_trip(&packet), packet);
+    }
+
+    #[test]
+    fn unicode_and_empty_contents_round_trip() {
+        for contents in ["", "héllo wörld", "😄 ¯\\_(ツ)_/¯", "日本語\n\"quoted\""] {
+            let packet = Packet {
+                packet_type: PacketType::NewMessage,
+                contents: contents.to_string(),
+                room: String::from("général"),
+                message: Some(Message {
+                    uid: 7,
+                    sender_name: String::from("ünïcode"),
+                    message: contents.to_string(),
+                    reply_to: Some(3),
+                    ..Default::default()
+                }),
+                nonce: 9,
+                ..Default::default()
+            };
+            assert_eq!(round_trip(&packet), packet);
+        }
+    }
+
+    #[test]
+    fn frames_are_only_compressed_when_allowed() {
+        let packet = Packet {
+            packet_type: PacketType::NewMessage,
+            contents: "repetitive ".repeat(500),
+            ..Default::default()
+        };
+        let (prefix, data) = encode(&packet, false, false).unwrap();
+        assert_eq!(prefix as usize, data.len());
+        let (prefix, data) = encode(&packet, true, false).unwrap();
+        assert_eq!(prefix, FLAG_DEFLATE | data.len() as u32);
+        assert_eq!(decode(prefix, &data).unwrap(), packet);
+        let (prefix, data) = encode(&packet, true, true).unwrap();
+        assert_eq!(prefix, FLAG_DEFLATE | FLAG_MSGPACK | data.len() as u32);
+        assert_eq!(decode(prefix, &data).unwrap(), packet);
+    }
+
+    #[test]
+    fn oversized_packets_are_refused_before_sending() {
+        let packet = Packet {
+            packet_type: PacketType::NewMessage,
+            contents: "x".repeat(MAX_PACKET_SIZE),
+            ..Default::default()
+        };
+        for (compress, msgpack) in [(false, false), (true, false), (true, true)] {
+            let error = encode(&packet, compress, msgpack).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn skipped_fields_round_trip() {
+        // Left out when empty, which mustn't confuse the fields after them
+        let empty = Packet {
+            packet_type: PacketType::FileChunk,
+            nonce: 3,
+            version: PROTOCOL_VERSION,
+            ..Default::default()
+        };
+        let full = Packet {
+            file: Some(FileInfo {
+                transfer_id: 1,
+                sequence: 2,
+                ..Default::default()
+            }),
+            features: vec![FEATURE_MSGPACK.to_string()],
+            readers: vec![4, 5],
+            history: vec![Message::default()],
+            ..empty.clone()
+        };
+        assert_eq!(round_trip(&empty), empty);
+        assert_eq!(round_trip(&full), full);
+    }
+
+    #[test]
+    fn unreadable_frames_are_rejected() {
+        assert_eq!(frame_length(10).unwrap(), 10);
+        for prefix in [MAX_PACKET_SIZE as u32 + 1, 1 << 26 | 10] {
+            assert_eq!(frame_length(prefix).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
@@ -0,0 +1,352 @@
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use std::sync::Arc;
+use tokio_rustls::{
+    TlsAcceptor,
+    rustls::{
+        ServerConfig,
+        pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+    },
+};
+
+// Where clients connect unless --bind says otherwise
+const DEFAULT_BIND: &str = "127.0.0.1:8080";
+
+// Packets the broadcast channel holds for clients that fall behind
+const DEFAULT_CHANNEL_CAPACITY: usize = 256;
+
+// Clients that send nothing for this long are disconnected
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
+// Clients connected at once before new connections are turned away
+const DEFAULT_MAX_CONNECTIONS: usize = 1000;
+
+// Connections one IP address may open per minute
+const DEFAULT_CONNECT_LIMIT: usize = 20;
+
+// Messages kept per room before the oldest are dropped
+const DEFAULT_HISTORY_LIMIT: usize = 1000;
+
+// Server settings collected from the command line
+#[derive(Default)]
+pub struct Config {
+    // Address the chat listener binds to, e.g. `0.0.0.0:8080` or `[::]:8080`
+    pub bind: String,
+
+    // Shortcode name (without colons) -> replacement text.
+    // Expansion is disabled when no map was loaded.
+    pub shortcodes: Option<HashMap<String, String>>,
+
+    // Lowercase words masked out of room messages. Filtering is disabled
+    // when no list was loaded.
+    pub filter: Option<HashSet<String>>,
+
+    // Where chat history is saved. History is kept in memory only when unset.
+    pub data_file: Option<PathBuf>,
+
+    // Wraps accepted connections in TLS. Connections are plaintext when unset.
+    pub tls: Option<TlsAcceptor>,
+
+    // Size of the broadcast channel. Clients that fall further behind than
+    // this are resynced from the room history.
+    pub channel_capacity: usize,
+
+    // Connections handled at once. Any more are told the server is full.
+    pub max_connections: usize,
+
+    // Connections one IP address may open per minute. Any more are closed
+    // straight away. Addresses aren't limited when unset.
+    pub connect_limit: Option<usize>,
+
+    // How long a client may go without sending anything before it is
+    // disconnected. Idle clients are kept forever when unset.
+    pub idle_timeout: Option<Duration>,
+
+    // Messages kept in each room's history. Older ones are dropped, and
+    // history grows forever when unset.
+    pub history_limit: Option<usize>,
+
+    // Clients must send this before they are let in. Anyone can join
+    // when unset.
+    pub password: Option<String>,
+
+    // Clients that log in with this are admins. Without it the first user
+    // to connect is.
+    pub admin_password: Option<String>,
+
+    // Message of the day sent to every client when it joins. Nothing is
+    // sent when unset.
+    pub motd: Option<String>,
+
+    // Write logs as JSON lines instead of human readable text
+    pub log_json: bool,
+
+    // Serve counters over HTTP on this port, at the address the chat
+    // listener is bound to. Off when unset.
+    pub metrics_port: Option<u16>,
+
+    // Offer clients MessagePack frames. Packets are JSON unless a client
+    // accepts.
+    pub msgpack: bool,
+}
+
+impl Config {
+    pub fn from_args() -> io::Result<Self> {
+        let mut config = Config {
+            bind: String::from(DEFAULT_BIND),
+            channel_capacity: DEFAULT_CHANNEL_CAPACITY,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            connect_limit: Some(DEFAULT_CONNECT_LIMIT),
+            idle_timeout: Some(DEFAULT_IDLE_TIMEOUT),
+            history_limit: Some(DEFAULT_HISTORY_LIMIT),
+            ..Default::default()
+        };
+        let mut args = env::args().skip(1);
+        let mut cert = None;
+        let mut key = None;
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--bind" => config.bind = next_value(&mut args, &arg)?,
+                "--shortcode-file" => {
+                    let path = next_value(&mut args, &arg)?;
+                    config.shortcodes = Some(load_shortcodes(&path)?);
+                },
+                "--filter-file" => {
+                    let path = next_value(&mut args, &arg)?;
+                    config.filter = Some(load_filter(&path)?);
+                },
+                "--data-file" => {
+                    config.data_file = Some(PathBuf::from(next_value(&mut args, &arg)?));
+                },
+                "--channel-capacity" => {
+                    let value = next_value(&mut args, &arg)?;
+                    config.channel_capacity = match value.parse() {
+                        Ok(capacity) if capacity > 0 => capacity,
+                        _ => return Err(invalid_input(format!("Invalid channel capacity: {}", value))),
+                    };
+                },
+                "--max-connections" => {
+                    let value = next_value(&mut args, &arg)?;
+                    config.max_connections = match value.parse() {
+                        Ok(max) if max > 0 => max,
+                        _ => return Err(invalid_input(format!("Invalid connection limit: {}", value))),
+                    };
+                },
+                "--connect-limit" => {
+                    let value = next_value(&mut args, &arg)?;
+                    config.connect_limit = match value.parse() {
+                        Ok(0) => None,
+                        Ok(limit) => Some(limit),
+                        Err(_) => return Err(invalid_input(format!("Invalid connect limit: {}", value))),
+                    };
+                },
+                "--idle-timeout" => {
+                    let value = next_value(&mut args, &arg)?;
+                    config.idle_timeout = match value.parse::<u64>() {
+                        Ok(0) => None,
+                        Ok(minutes) => Some(Duration::from_secs(minutes * 60)),
+                        Err(_) => return Err(invalid_input(format!("Invalid idle timeout: {}", value))),
+                    };
+                },
+                "--history-limit" => {
+                    let value = next_value(&mut args, &arg)?;
+                    config.history_limit = match value.parse() {
+                        Ok(0) => None,
+                        Ok(limit) => Some(limit),
+                        Err(_) => return Err(invalid_input(format!("Invalid history limit: {}", value))),
+                    };
+                },
+                "--password" => config.password = Some(next_value(&mut args, &arg)?),
+                "--admin-password" => config.admin_password = Some(next_value(&mut args, &arg)?),
+                "--password-file" => {
+                    let path = next_value(&mut args, &arg)?;
+                    config.password = Some(load_password(&path)?);
+                },
+                "--motd-file" => {
+                    let path = next_value(&mut args, &arg)?;
+                    config.motd = load_motd(&path)?;
+                },
+                "--metrics-port" => {
+                    let value = next_value(&mut args, &arg)?;
+                    config.metrics_port = match value.parse() {
+                        Ok(port) if port > 0 => Some(port),
+                        _ => return Err(invalid_input(format!("Invalid metrics port: {}", value))),
+                    };
+                },
+                "--log-json" => config.log_json = true,
+                "--msgpack" => config.msgpack = true,
+                "--cert" => cert = Some(PathBuf::from(next_value(&mut args, &arg)?)),
+                "--key" => key = Some(PathBuf::from(next_value(&mut args, &arg)?)),
+                _ => return Err(invalid_input(format!("Unknown argument: {}", arg))),
+            }
+        }
+
+        config.tls = match (cert, key) {
+            (Some(cert), Some(key)) => Some(load_tls(&cert, &key)?),
+            (None, None) => None,
+            _ => return Err(invalid_input("--cert and --key must be given together".to_string())),
+        };
+
+        Ok(config)
+    }
+
+    // Replace every known `:name:` shortcode in the text.
+    // Unknown shortcodes are left as they are.
+    pub fn expand_shortcodes(&self, text: &str) -> String {
+        let Some(shortcodes) = &self.shortcodes else {
+            return text.to_string();
+        };
+
+        let mut result = String::with_capacity(text.len());
+        let mut rest = text;
+        while let Some(start) = rest.find(':') {
+            result.push_str(&rest[..start]);
+            let after = &rest[start + 1..];
+
+            match after.find(':') {
+                Some(end) => match shortcodes.get(&after[..end]) {
+                    Some(replacement) => {
+                        result.push_str(replacement);
+                        rest = &after[end + 1..];
+                    },
+                    None => {
+                        // The closing colon may open the next shortcode
+                        result.push(':');
+                        rest = after;
+                    },
+                },
+                None => {
+                    result.push(':');
+                    rest = after;
+                },
+            }
+        }
+        result.push_str(rest);
+
+        result
+    }
+
+    // Replace every filtered word with asterisks. Only whole words match,
+    // so a filtered word inside a longer one is left alone.
+    pub fn filter_words(&self, text: &str) -> String {
+        let Some(filter) = &self.filter else {
+            return text.to_string();
+        };
+
+        let mut result = String::with_capacity(text.len());
+        let mut word = String::new();
+        let flush = |word: &mut String, result: &mut String| {
+            if filter.contains(&word.to_lowercase()) {
+                result.extend(word.chars().map(|_| '*'));
+            }
+            else {
+                result.push_str(word);
+            }
+            word.clear();
+        };
+        for c in text.chars() {
+            if c.is_alphanumeric() {
+                word.push(c);
+            }
+            else {
+                flush(&mut word, &mut result);
+                result.push(c);
+            }
+        }
+        flush(&mut word, &mut result);
+
+        result
+    }
+}
+
+fn next_value(args: &mut impl Iterator<Item = String>, flag: &str) -> io::Result<String> {
+    args.next()
+        .ok_or_else(|| invalid_input(format!("Missing value for {}", flag)))
+}
+
+fn invalid_input(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+// The shortcode file is a JSON object, e.g. {"smile": "😄", "shrug": "¯\\_(ツ)_/¯"}
+fn load_shortcodes(path: &str) -> io::Result<HashMap<String, String>> {
+    let data = fs::read_to_string(path)?;
+    serde_json::from_str(&data)
+        .map_err(|error| invalid_input(format!("Invalid shortcode file {}: {}", path, error)))
+}
+
+// The whole file is the password, minus any trailing newline
+fn load_password(path: &str) -> io::Result<String> {
+    let data = fs::read_to_string(path)?;
+    let password = data.trim_end_matches(['\r', '\n']);
+    if password.is_empty() {
+        return Err(invalid_input(format!("Password file {} is empty", path)));
+    }
+    Ok(password.to_string())
+}
+
+// Kept as written apart from trailing blank lines. An empty file means
+// there is nothing to say.
+fn load_motd(path: &str) -> io::Result<Option<String>> {
+    let data = fs::read_to_string(path)?;
+    let motd = data.trim_end();
+    Ok((!motd.is_empty()).then(|| motd.to_string()))
+}
+
+// One word per line. Blank lines and lines starting with '#' are skipped.
+fn load_filter(path: &str) -> io::Result<HashSet<String>> {
+    let data = fs::read_to_string(path)?;
+    Ok(data.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_lowercase)
+        .collect())
+}
+
+// Both files are PEM encoded. The certificate file may hold a full chain.
+fn load_tls(cert_path: &Path, key_path: &Path) -> io::Result<TlsAcceptor> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|error| invalid_input(format!("Invalid certificate file {}: {}", cert_path.display(), error)))?;
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|error| invalid_input(format!("Invalid key file {}: {}", key_path.display(), error)))?;
+
+    let server_config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|error| invalid_input(format!("Invalid certificate or key: {}", error)))?;
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_shortcodes_are_expanded_and_the_rest_kept() {
+        let config = Config {
+            shortcodes: Some(HashMap::from([
+                (String::from("smile"), String::from("😄")),
+                (String::from("wave"), String::from("👋")),
+            ])),
+            ..Default::default()
+        };
+
+        assert_eq!(config.expand_shortcodes("hi :smile:"), "hi 😄");
+        assert_eq!(config.expand_shortcodes(":nope: and :smile"), ":nope: and :smile");
+        assert_eq!(config.expand_shortcodes(":smile::wave:"), "😄👋");
+        assert_eq!(config.expand_shortcodes("::smile:"), ":😄");
+        assert_eq!(config.expand_shortcodes("a :: b"), "a :: b");
+        assert_eq!(config.expand_shortcodes("time 12:30:wave:"), "time 12:30👋");
+    }
+
+    #[test]
+    fn shortcodes_are_left_alone_without_a_file() {
+        assert_eq!(Config::default().expand_shortcodes(":smile:"), ":smile:");
+    }
+}
@@ -1,251 +1,1903 @@
-use std::collections::HashMap;
-use core::fmt;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::{Arc};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use serde::{Serialize, Deserialize};
+use tokio_rustls::{TlsAcceptor, server::TlsStream};
+use tracing::{Instrument, Span, debug, field, info, info_span, warn};
+use tracing_subscriber::EnvFilter;
 use tokio::{
-    io::{AsyncWriteExt, AsyncReadExt, BufReader, BufWriter},
+    io::{self as tokio_io, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, BufWriter},
     net::{TcpStream, TcpListener},
+    signal,
+    task::JoinSet,
     sync::{
         Mutex,
+        Notify,
+        Semaphore,
+        mpsc,
         broadcast::{self, Sender},
     },
+    time::{self, Instant},
 };
 
+mod config;
+mod metrics;
+mod net;
+mod persist;
+use config::Config;
+use net::{
+    FEATURE_DEFLATE, FEATURE_MSGPACK, HISTORY_PAGE, MAX_PACKET_SIZE, Message, PROTOCOL_VERSION, Packet, PacketType,
+    PacketWriter, RECEIPT_WINDOW, read_packet, write_packet,
+};
+
+// Room every client starts out in
+const DEFAULT_ROOM: &str = "general";
+
+// How often clients are pinged, and how long they have to answer
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+const PONG_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Each client may send this many chat packets per period. Bursts up to
+// the full amount are allowed, then they trickle back in over the period.
+const RATE_LIMIT_MESSAGES: f64 = 5.0;
+const RATE_LIMIT_PERIOD: Duration = Duration::from_secs(2);
+
+// Connections from one IP address are counted over this window to
+// decide whether it is flooding the server
+const CONNECT_WINDOW: Duration = Duration::from_secs(60);
+
+// Users may change their name once per interval. The last few names
+// they went by are remembered.
+const NAME_CHANGE_INTERVAL: Duration = Duration::from_secs(10);
+const NAME_HISTORY_LEN: usize = 5;
+
+// Longest username allowed, in characters
+const MAX_NAME_LENGTH: usize = 32;
+
+// Longest message or whisper, in characters
+const MAX_MESSAGE_LENGTH: usize = 2000;
+
+// Largest file clients may send each other
+const MAX_FILE_SIZE: u64 = 10 << 20;
+
+// Most dice and sides a single /roll may use
+const MAX_DICE: u32 = 100;
+const MAX_SIDES: u32 = 1000;
+
+// Most messages a single HistoryRequest is answered with
+const MAX_HISTORY_PAGE: u32 = 200;
+
+// Most matches an admin's /grep is answered with, newest first
+const MAX_GREP_RESULTS: usize = 50;
+
+// Bytes of messages a history page or /grep answer is filled with. The
+// rest of MAX_PACKET_SIZE is left for the other fields of the packet.
+const PAGE_BYTES: usize = MAX_PACKET_SIZE - (64 << 10);
+
+// Sent above the oldest message of a room that has had history dropped
+const TRIMMED_NOTICE: &str = "Earlier messages are no longer available";
+
+// How long a client has to finish the TLS handshake
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How long a connection turned away for being over the limit is given
+// to receive the notice
+const REJECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How long shutdown waits for clients to receive the shutdown notice
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Clients are told a user is active at most this often, and work out how
+// long they have been idle from that
+const ACTIVITY_REPORT_INTERVAL: Duration = Duration::from_secs(60);
+
 #[derive(Default, Clone, Serialize, Deserialize)]
 struct User {
     uid: u32,
     name: String,
     messages: Vec<u32>,
-}
+    room: String,
 
-#[derive(Default, Clone, Serialize, Deserialize)]
-struct Message {
-    uid: u32,
-    sender_id: u32,
-    message: String,
-}
+    // Reason given with /away, or None while the user is online
+    away: Option<String>,
 
-impl fmt::Display for Message {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}: {}", self.sender_id, self.message)
-    }
+    // Names the user went by before, most recent last
+    previous_names: Vec<String>,
+
+    // Allowed to kick other users
+    is_admin: bool,
+
+    // Unix time the user last sent a message
+    last_active: u64,
+
+    // Unix time the user connected
+    connected_at: u64,
+
+    // Name color picked with /color. Clients choose one from the id when
+    // unset.
+    color: Option<String>,
+
+    // Where the user connected from, shown to admins only
+    #[serde(skip)]
+    address: Option<SocketAddr>,
 }
 
 #[derive(Default)]
 struct ServerState {
     user_list: HashMap<u32, User>,
-    message_list: Vec<Message>,
+
+    // Per-client channels for packets addressed to a single user
+    connections: HashMap<u32, mpsc::UnboundedSender<Packet>>,
+
+    // Message history of each room, oldest first
+    rooms: HashMap<String, Vec<Message>>,
+
+    // Rooms that have dropped old messages to stay under the history limit
+    trimmed_rooms: HashSet<String>,
+
+    // Who has seen each recent message, by message id
+    receipts: HashMap<u32, HashSet<u32>>,
+
+    // Message ids are handed out in order so they are short enough to type.
+    // Ids are never reused, even once their message has been dropped.
+    last_message_id: u32,
+
+    // Signalled whenever something that is persisted changes
+    dirty: Arc<Notify>,
+
+    // Room messages and whispers users have sent since the server started.
+    // Away auto-replies aren't counted.
+    messages_sent: u64,
+}
+
+impl ServerState {
+    fn mark_dirty(&self) {
+        self.dirty.notify_one();
+    }
+
+    fn new_message_id(&mut self) -> u32 {
+        self.last_message_id += 1;
+        self.last_message_id
+    }
+
+    // Add a message to a room's history, dropping the oldest ones once the
+    // room holds more than the limit
+    fn store_message(&mut self, room: &str, message: Message, limit: Option<usize>) {
+        self.rooms.entry(room.to_string()).or_default().push(message);
+        self.trim_room(room, limit);
+    }
+
+    fn trim_room(&mut self, room: &str, limit: Option<usize>) {
+        let Some(limit) = limit else {
+            return;
+        };
+        if let Some(messages) = self.rooms.get_mut(room) && messages.len() > limit {
+            messages.drain(..messages.len() - limit);
+            self.trimmed_rooms.insert(room.to_string());
+        }
+    }
+
+    // Record that a user has seen a recent message in their room, returning
+    // everyone who has seen it if that was news
+    fn add_receipt(&mut self, room: &str, uid: u32, reader: u32) -> Option<Vec<u32>> {
+        let last = self.last_message_id;
+        if last.saturating_sub(uid) >= RECEIPT_WINDOW {
+            return None;
+        }
+        // Ids only grow, so the search can stop at older messages
+        let in_room = self.rooms.get(room).is_some_and(|messages| {
+            messages.iter().rev().take_while(|message| message.uid >= uid).any(|message| message.uid == uid)
+        });
+        if !in_room {
+            return None;
+        }
+
+        self.receipts.retain(|uid, _| last.saturating_sub(*uid) < RECEIPT_WINDOW);
+        let readers = self.receipts.entry(uid).or_default();
+        if !readers.insert(reader) {
+            return None;
+        }
+        let mut readers: Vec<u32> = readers.iter().copied().collect();
+        readers.sort();
+        Some(readers)
+    }
+
+    // Up to `limit` messages from a room sent before message `before`, or
+    // the newest ones if `before` is 0, and whether they reach back to the
+    // oldest message kept
+    fn history_page(&self, room: &str, before: u32, limit: usize) -> (Vec<Message>, bool) {
+        let messages = self.rooms.get(room).map(Vec::as_slice).unwrap_or_default();
+        let end = match before {
+            0 => messages.len(),
+            before => messages.partition_point(|message| message.uid < before),
+        };
+        let start = end.saturating_sub(limit);
+        (messages[start..end].to_vec(), start == 0)
+    }
+
+    // The newest `limit` messages in any room whose text contains the
+    // lowercase `query`, grouped by room and oldest first, along with how
+    // many matched in all. Fewer are returned if they wouldn't fit in
+    // PAGE_BYTES.
+    fn grep(&self, query: &str, limit: usize) -> (BTreeMap<String, Vec<Message>>, usize) {
+        let mut matches: Vec<(&String, &Message)> = self.rooms.iter()
+            .flat_map(|(room, messages)| messages.iter().map(move |message| (room, message)))
+            .filter(|(_, message)| message.message.to_lowercase().contains(query))
+            .collect();
+        matches.sort_by_key(|(_, message)| message.uid);
+
+        let total = matches.len();
+        let mut size = 0;
+        let newest: Vec<(&String, &Message)> = matches.into_iter()
+            .rev()
+            .take(limit)
+            .take_while(|(_, message)| {
+                size += encoded_size(message);
+                size <= PAGE_BYTES
+            })
+            .collect();
+        let mut rooms: BTreeMap<String, Vec<Message>> = BTreeMap::new();
+        for (room, message) in newest.into_iter().rev() {
+            rooms.entry(room.clone()).or_default().push(message.clone());
+        }
+        (rooms, total)
+    }
+
+    // Find a stored message by id, along with the room it was sent in
+    fn find_message_mut(&mut self, uid: u32) -> Option<(String, &mut Message)> {
+        self.rooms.iter_mut().find_map(|(room, messages)| {
+            messages.iter_mut()
+                .find(|message| message.uid == uid)
+                .map(|message| (room.clone(), message))
+        })
+    }
+
+    // Whether a user other than `uid` goes by this name, ignoring case
+    fn name_taken(&self, uid: u32, name: &str) -> bool {
+        self.user_list.values().any(|user| {
+            user.uid != uid && user.name.eq_ignore_ascii_case(name)
+        })
+    }
+
+    // Make a name unique among the other connected users by appending
+    // a numeric suffix, e.g. `alice` -> `alice#2`. Long names are cut
+    // short so the result still fits in MAX_NAME_LENGTH.
+    fn unique_name(&self, uid: u32, name: &str) -> String {
+        let mut candidate = name.to_string();
+        let mut suffix = 2;
+        while self.name_taken(uid, &candidate) {
+            let suffix_text = format!("#{}", suffix);
+            let base: String = name.chars().take(MAX_NAME_LENGTH - suffix_text.len()).collect();
+            candidate = format!("{}{}", base.trim_end(), suffix_text);
+            suffix += 1;
+        }
+        candidate
+    }
 }
 
-#[derive(Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
-enum PacketType {
-    #[default]
-    None,
-    IDAssign,
-    UserConnected,
-    UserDisconnected,
-    UserList,
-    UsernameChange,
-    NewMessage,
+// Roughly how many bytes a message takes up in a packet
+fn encoded_size(message: &Message) -> usize {
+    serde_json::to_vec(message).map_or(PAGE_BYTES, |data| data.len() + 1)
 }
 
-#[derive(Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
-struct Packet {
-    packet_type: PacketType, 
-    
-    user_id: u32,
-    contents: String,
-} 
+// Keep as many of the newest messages of a page as fit in PAGE_BYTES,
+// returning whether any had to be left out
+fn fit_page(messages: &mut Vec<Message>) -> bool {
+    let mut size = 0;
+    let fit = messages.iter()
+        .rev()
+        .take_while(|message| {
+            size += encoded_size(message);
+            size <= PAGE_BYTES
+        })
+        .count();
+    let cut = fit < messages.len();
+    messages.drain(..messages.len() - fit);
+    cut
+}
+
+// Token bucket limiting how fast a single client can send messages
+struct RateLimiter {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    fn new() -> Self {
+        Self {
+            tokens: RATE_LIMIT_MESSAGES,
+            last_refill: Instant::now(),
+        }
+    }
+
+    // Take a token if one is available
+    fn try_acquire(&mut self) -> bool {
+        let now = Instant::now();
+        let refill = now.duration_since(self.last_refill).as_secs_f64() / RATE_LIMIT_PERIOD.as_secs_f64();
+        self.tokens = (self.tokens + refill * RATE_LIMIT_MESSAGES).min(RATE_LIMIT_MESSAGES);
+        self.last_refill = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        }
+        else {
+            false
+        }
+    }
+}
+
+// Sliding window of recent connections per IP address, so one address
+// opening connections in a loop can't spawn endless client tasks
+struct ConnectLimiter {
+    limit: usize,
+    recent: HashMap<IpAddr, VecDeque<Instant>>,
+}
+
+impl ConnectLimiter {
+    fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            recent: HashMap::new(),
+        }
+    }
 
-async fn handle_client(
-    mut tcp_stream: TcpStream,
+    // Record a connection from the address if it hasn't used up its
+    // connections for the current window
+    fn try_connect(&mut self, ip: IpAddr, now: Instant) -> bool {
+        let recent = self.recent.entry(ip).or_default();
+        while recent.front().is_some_and(|&time| now.duration_since(time) >= CONNECT_WINDOW) {
+            recent.pop_front();
+        }
+        let allowed = recent.len() < self.limit;
+        if allowed {
+            recent.push_back(now);
+        }
+
+        // Forget addresses that have gone quiet so the map doesn't grow forever
+        if self.recent.len() > 1024 {
+            self.recent.retain(|_, recent| {
+                recent.back().is_some_and(|&time| now.duration_since(time) < CONNECT_WINDOW)
+            });
+        }
+        allowed
+    }
+}
+
+// Check a requested username, returning the trimmed name or the reason
+// it was rejected
+fn validate_name(name: &str) -> Result<String, &'static str> {
+    let name = name.trim();
+    if name.is_empty() {
+        Err("Username must not be empty")
+    }
+    else if name.chars().any(char::is_control) {
+        Err("Username must not contain control characters")
+    }
+    else if name.chars().count() > MAX_NAME_LENGTH {
+        Err("Username must be at most 32 characters")
+    }
+    else {
+        Ok(name.to_string())
+    }
+}
+
+// Colors /color accepts by name, the same ones clients pick from
+const NAME_COLORS: &[&str] = &[
+    "cyan", "green", "yellow", "blue", "magenta",
+    "lightred", "lightgreen", "lightyellow", "lightblue", "lightmagenta", "lightcyan",
+];
+
+// Check a requested name color, returning it lowercased, or None to go
+// back to the default
+fn validate_color(color: &str) -> Result<Option<String>, &'static str> {
+    let color = color.trim().to_lowercase();
+    let hex = color.strip_prefix('#').is_some_and(|hex| hex.len() == 6 && hex.chars().all(|c| c.is_ascii_hexdigit()));
+    if color.is_empty() {
+        Ok(None)
+    }
+    else if hex || NAME_COLORS.contains(&color.as_str()) {
+        Ok(Some(color))
+    }
+    else {
+        Err("Unknown color, use a name like lightblue or a hex code like #ff8800")
+    }
+}
+
+// Roll dice written as `NdM`, e.g. `2d6`. The count may be left out to
+// roll a single die.
+fn roll_dice(spec: &str) -> Result<Vec<u32>, &'static str> {
+    let spec = spec.trim().to_lowercase();
+    let (count, sides) = spec.split_once('d').ok_or("Dice must be written like 2d6")?;
+    let count = if count.is_empty() { Ok(1) } else { count.parse::<u32>() };
+    let (Ok(count), Ok(sides)) = (count, sides.parse::<u32>()) else {
+        return Err("Dice must be written like 2d6");
+    };
+    if !(1..=MAX_DICE).contains(&count) || !(2..=MAX_SIDES).contains(&sides) {
+        return Err("You can roll 1 to 100 dice with 2 to 1000 sides");
+    }
+    Ok((0..count).map(|_| rand::random_range(1..=sides)).collect())
+}
+
+// Announces a user going away, or coming back when the reason is empty
+fn status_packet(uid: u32, reason: &str) -> Packet {
+    Packet {
+        packet_type: PacketType::StatusChange,
+        user_id: uid,
+        contents: reason.to_string(),
+        ..Default::default()
+    }
+}
+
+// Send a packet to every client. This only fails once nobody is
+// subscribed, which is worth seeing while debugging but nothing to act on.
+fn send_to_all(sender: &Sender<Packet>, packet: Packet) {
+    if let Err(error) = sender.send(packet) {
+        debug!(packet_type = ?error.0.packet_type, "No clients to broadcast to");
+    }
+}
+
+// Set or clear the user's away status and let everyone know
+async fn set_away(
+    state: &Mutex<ServerState>,
+    sender: &Sender<Packet>,
+    local: &mut User,
+    away: Option<String>,
+) {
+    local.away = away;
+    if let Some(user) = state.lock().await.user_list.get_mut(&local.uid) {
+        user.away = local.away.clone();
+    }
+    send_to_all(sender, status_packet(local.uid, local.away.as_deref().unwrap_or_default()));
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or_default()
+}
+
+// Move the user into a room, then confirm the switch and send the room's
+// history so the client can repopulate its message pane.
+async fn join_room<W: AsyncWrite + Unpin>(
+    writer: &mut PacketWriter<W>,
+    state: &Mutex<ServerState>,
+    local: &mut User,
+    room: &str,
+) -> io::Result<()> {
+    local.room = room.to_string();
+
+    // Only the newest page is sent, the client asks for older ones as the
+    // user scrolls up
+    let (history, start, trimmed) = {
+        let mut s = state.lock().await;
+        if let Some(user) = s.user_list.get_mut(&local.uid) {
+            user.room = local.room.clone();
+        }
+        let (history, start) = s.history_page(room, 0, HISTORY_PAGE as usize);
+        (history, start, s.trimmed_rooms.contains(room))
+    };
+
+    // Let the client know there was more before the history it gets
+    let join_packet = Packet {
+        packet_type: PacketType::JoinRoom,
+        user_id: local.uid,
+        room: local.room.clone(),
+        contents: if start && trimmed { String::from(TRIMMED_NOTICE) } else { String::new() },
+        ..Default::default()
+    };
+    write_packet(writer, &join_packet).await?;
+
+    for message in history {
+        let history_packet = Packet {
+            packet_type: PacketType::MessageHistory,
+            user_id: message.sender_id,
+            room: local.room.clone(),
+            message: Some(message),
+            ..Default::default()
+        };
+        write_packet(writer, &history_packet).await?;
+    }
+
+    Ok(())
+}
+
+// Ask for a password and wait for the client to send it, returning
+// whether it was the admin password. A wrong password ends the connection
+// if the server requires one.
+async fn authenticate<R, W>(reader: &mut R, writer: &mut PacketWriter<W>, config: &Config) -> io::Result<bool>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let request = Packet {
+        packet_type: PacketType::Auth,
+        contents: String::from("Enter the server password"),
+        ..Default::default()
+    };
+    write_packet(writer, &request).await?;
+
+    let packet = read_packet(reader).await.map_err(|error| match error.kind() {
+        io::ErrorKind::UnexpectedEof => io::Error::new(
+            error.kind(),
+            "Client disconnected before sending a password",
+        ),
+        _ => error,
+    })?;
+
+    let given = match packet.packet_type {
+        PacketType::Auth => packet.contents,
+        _ => String::new(),
+    };
+    if config.admin_password.as_deref().is_some_and(|admin| passwords_match(&given, admin)) {
+        return Ok(true);
+    }
+    let reason = match config.password.as_deref() {
+        None => return Ok(false),
+        Some(password) if passwords_match(&given, password) => return Ok(false),
+        Some(_) if given.is_empty() => "This server requires a password",
+        Some(_) => "Incorrect password",
+    };
+    write_packet(writer, &Packet::error(reason)).await?;
+    Err(io::Error::new(io::ErrorKind::PermissionDenied, "Client sent the wrong password"))
+}
+
+// Compares every byte so the time taken doesn't hint at how much matched
+fn passwords_match(given: &str, expected: &str) -> bool {
+    given.len() == expected.len() &&
+        given.bytes().zip(expected.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+// Works the same for plain TCP and TLS streams
+async fn handle_client<S: AsyncRead + AsyncWrite + Send + 'static>(
+    stream: S,
+    address: SocketAddr,
     sender: Sender<Packet>,
     state: Arc<Mutex<ServerState>>,
+    config: Arc<Config>,
 ) -> std::io::Result<()> {
     // Subscribe to broadcast channel
     let mut receiver = sender.subscribe();
 
-    // Split TCP Stream
-    let (read, write) = tcp_stream.split();
+    // Split stream
+    let (read, write) = tokio_io::split(stream);
     let mut reader = BufReader::new(read);
-    let mut writer = BufWriter::new(write);
+    let mut writer = PacketWriter::new(BufWriter::new(write));
+
+    // Private servers let nobody in without the password, and admins
+    // identify themselves with theirs
+    let is_admin = if config.password.is_some() || config.admin_password.is_some() {
+        authenticate(&mut reader, &mut writer, &config).await?
+    }
+    else {
+        false
+    };
 
     // Send UID to client
     let uid: u32 = rand::random::<u32>();
+    Span::current().record("uid", uid);
+    let mut features = vec![FEATURE_DEFLATE.to_string()];
+    if config.msgpack {
+        features.push(FEATURE_MSGPACK.to_string());
+    }
     let packet: Packet = Packet {
         packet_type: PacketType::IDAssign,
         user_id: uid,
+        features,
+        version: PROTOCOL_VERSION,
         ..Default::default()
     };
-    let data = serde_json::to_string(&packet)
-        .expect("[ERROR] Failed to serialize packet");
-    writer.write(data.as_bytes()).await?;
-    writer.flush().await?;
+    write_packet(&mut writer, &packet).await?;
 
-    // Get username from client
-    let mut buffer = [0; 1024];
-    let mut packet = loop {
-        let _ = reader.read(&mut buffer).await;
-        let mut data = serde_json::Deserializer::from_slice(&buffer);
-        let packet: Packet = Packet::deserialize(&mut data)
-            .expect("[ERROR] Failed to deserialize packet");
+    // Get username from client, asking again until it sends a valid one
+    let name = loop {
+        let packet = read_packet(&mut reader).await.map_err(|error| match error.kind() {
+            io::ErrorKind::UnexpectedEof => io::Error::new(
+                error.kind(),
+                "Client disconnected before sending a username",
+            ),
+            _ => error,
+        })?;
 
         if packet.packet_type == PacketType::UsernameChange {
-            break packet;
+            // Better to say so now than have the client choke on a packet later
+            if packet.version != PROTOCOL_VERSION {
+                let mismatch = Packet {
+                    packet_type: PacketType::VersionMismatch,
+                    contents: format!(
+                        "Server uses protocol version {} but this client uses version {}, please update",
+                        PROTOCOL_VERSION,
+                        packet.version,
+                    ),
+                    version: PROTOCOL_VERSION,
+                    ..Default::default()
+                };
+                write_packet(&mut writer, &mismatch).await?;
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Client uses protocol version {}", packet.version),
+                ));
+            }
+
+            // Clients too old to know about compression don't ask for it
+            if packet.features.iter().any(|feature| feature == FEATURE_DEFLATE) {
+                writer.compress = true;
+            }
+            if config.msgpack && packet.features.iter().any(|feature| feature == FEATURE_MSGPACK) {
+                writer.msgpack = true;
+            }
+            match validate_name(&packet.contents) {
+                Ok(name) => break name,
+                Err(reason) => write_packet(&mut writer, &Packet::error(reason)).await?,
+            }
         }
     };
 
     // Create user object for new client
-    packet.contents = packet.contents.trim().to_string();
-    println!("[SERVER] New User: {}", packet.contents.clone());
     let mut local: User = User {
-        uid: uid,
-        name: packet.contents,
+        uid,
+        name,
+        is_admin,
+        last_active: unix_time(),
+        connected_at: unix_time(),
+        address: Some(address),
         ..Default::default()
     };
     
-    // Add user to state
-    {
+    // Channel for packets sent only to this client
+    let (direct_sender, mut direct) = mpsc::unbounded_channel::<Packet>();
+
+    // Add user to state. Nothing is written to the client until the lock
+    // is released, so a slow reader can't hold up everyone else.
+    let welcome = {
         let mut s = state.lock().await;
+        let mut welcome = Vec::new();
+
+        // Tell the client if its name had to be changed
+        let name = s.unique_name(local.uid, &local.name);
+        if name != local.name {
+            local.name = name;
+            welcome.push(Packet {
+                packet_type: PacketType::UsernameChange,
+                user_id: local.uid,
+                contents: local.name.clone(),
+                ..Default::default()
+            });
+        }
+        Span::current().record("name", local.name.as_str());
+
+        // Someone has to be able to moderate when no admin password is set
+        if config.admin_password.is_none() && s.user_list.is_empty() {
+            local.is_admin = true;
+        }
+        info!(admin = local.is_admin, "New user");
+
         s.user_list.insert(local.uid, local.clone());
+        s.connections.insert(local.uid, direct_sender);
 
         // Broadcast new user packet
         let new_user_packet = Packet {
             packet_type: PacketType::UserConnected,
             user_id: local.uid,
             contents: local.name.clone(),
+            ..Default::default()
         };
-        let _ = sender.send(new_user_packet);
+        send_to_all(&sender, new_user_packet);
 
-        // Send client list of users
-        for (_, user) in &s.user_list {
+        // Send client list of users, alphabetically
+        let mut users: Vec<&User> = s.user_list.values().collect();
+        users.sort_by(|a, b| a.name.to_lowercase().cmp(&b.name.to_lowercase()).then(a.uid.cmp(&b.uid)));
+        for user in users {
             // Don't send the local user a copy of themself
             if user.uid == local.uid {
                 continue;
             }
 
-            let user_list_packet = Packet {
+            welcome.push(Packet {
                 packet_type: PacketType::UserList,
                 user_id: user.uid,
                 contents: user.name.clone(),
+                idle: unix_time().saturating_sub(user.last_active),
+                ..Default::default()
+            });
+
+            if let Some(color) = &user.color {
+                welcome.push(Packet {
+                    packet_type: PacketType::ColorChange,
+                    user_id: user.uid,
+                    contents: color.clone(),
+                    ..Default::default()
+                });
+            }
+
+            if let Some(reason) = &user.away {
+                welcome.push(status_packet(user.uid, reason));
+            }
+        }
+        welcome
+    };
+
+    // Read packets on a separate task. Reading a frame is not cancel
+    // safe, so it can't be raced directly against the broadcast channel.
+    let (incoming_sender, mut incoming) = mpsc::channel::<io::Result<Packet>>(32);
+    let reader_task = tokio::spawn(async move {
+        loop {
+            let result = read_packet(&mut reader).await;
+            let failed = result.is_err();
+            if incoming_sender.send(result).await.is_err() || failed {
+                break;
+            }
+        }
+    }.in_current_span());
+
+    // Heartbeat state. A deadline is set when a ping goes out and cleared
+    // when the pong comes back.
+    let mut heartbeat = time::interval_at(Instant::now() + PING_INTERVAL, PING_INTERVAL);
+    let mut pong_deadline: Option<Instant> = None;
+
+    // Only packets that reach other users count towards the limit
+    let mut rate_limiter = RateLimiter::new();
+    let mut last_name_change: Option<Instant> = None;
+
+    // Pongs and read receipts are sent without the user doing anything, so
+    // they don't count as activity
+    let mut last_activity = Instant::now();
+    let mut activity_reported = Instant::now();
+
+    // Sent by the client with /quit and passed on to the others
+    let mut parting_message = String::new();
+
+    // Main client handle loop. The user is in the state from here on, so
+    // whatever happens they are taken out again afterwards.
+    let result: io::Result<()> = async {
+        for packet in &welcome {
+            write_packet(&mut writer, packet).await?;
+        }
+
+        if let Some(motd) = &config.motd {
+            let motd = Packet {
+                packet_type: PacketType::Motd,
+                contents: motd.clone(),
+                ..Default::default()
             };
-            let user_data = serde_json::to_string(&user_list_packet)
-                .expect("[ERROR] Failed to serialize packet");
-            writer.write(user_data.as_bytes()).await?;
-            writer.flush().await?;
+            write_packet(&mut writer, &motd).await?;
         }
-    }
 
-    // Main client handle loop
-    loop {
-        let mut buffer = [0; 1024];
-        
-        // This allows us to process multiple different "types" of
-        // messages from the client. 
-        tokio::select! {
-            // Process data read from the client
-            socket_read_result = reader.read(&mut buffer) => {
-                let num_bytes: usize = socket_read_result?;
+        // Put the client in the default room and send them its history
+        join_room(&mut writer, &state, &mut local, DEFAULT_ROOM).await?;
 
-                if num_bytes == 0 {
-                    break;
-                }
+        loop {
+            // This allows us to process multiple different "types" of
+            // messages from the client. 
+            tokio::select! {
+                // Process data read from the client
+                read_result = incoming.recv() => {
+                    let mut packet = match read_result {
+                        Some(Ok(packet)) => packet,
+                        Some(Err(error)) if error.kind() != io::ErrorKind::UnexpectedEof => {
+                            return Err(error);
+                        },
+                        // Client closed the connection
+                        _ => return Ok(()),
+                    };
+                    if !matches!(packet.packet_type, PacketType::Pong | PacketType::ReadReceipt) {
+                        last_activity = Instant::now();
+                    }
 
-                // Convert recieved data into packet object
-                //let packet: Packet = serde_json::from_str(&buffer).unwrap();
-                let mut data = serde_json::Deserializer::from_slice(&buffer);    
-                let packet = Packet::deserialize(&mut data)
-                    .expect("[ERROR] Failed to deserialize packet");
-
-                // Handle Packet
-                let packet_clone = packet.clone();
-                match packet.packet_type {
-                    PacketType::UsernameChange => {
-                        local.name = packet.contents.clone();
-                        {
-                            let mut s = state.lock().await;
-                            let user = s.user_list.get_mut(&local.uid).unwrap();
-                            user.name = packet.contents.clone();
-                        }
-                    },
-                    PacketType::NewMessage => {
-                        let message = Message {
-                            uid: rand::random::<u32>(),    
-                            sender_id: local.uid,
-                            message: packet.contents.trim().to_string(),    
+                    // Whatever id the client puts in a packet, it is from
+                    // the user on this connection
+                    packet.user_id = local.uid;
+
+                    let limited = matches!(
+                        packet.packet_type,
+                        PacketType::NewMessage |
+                        PacketType::PrivateMessage |
+                        PacketType::EditMessage |
+                        PacketType::DiceRoll
+                    );
+                    if limited && !rate_limiter.try_acquire() {
+                        let notice = Packet {
+                            packet_type: PacketType::RateLimited,
+                            contents: String::from("You are sending messages too fast, slow down"),
+                            nonce: packet.nonce,
+                            ..Default::default()
                         };
+                        write_packet(&mut writer, &notice).await?;
+                        continue;
+                    }
+                    if limited {
+                        if let Some(user) = state.lock().await.user_list.get_mut(&local.uid) {
+                            user.last_active = unix_time();
+                        }
+                        if activity_reported.elapsed() >= ACTIVITY_REPORT_INTERVAL {
+                            activity_reported = Instant::now();
+                            let active = Packet {
+                                packet_type: PacketType::UserActive,
+                                user_id: local.uid,
+                                ..Default::default()
+                            };
+                            send_to_all(&sender, active);
+                        }
+                    }
+
+                    // Handle Packet. Only packets other clients need to know
+                    // about are redirected to the broadcast channel.
+                    let broadcast = match packet.packet_type {
+                        PacketType::UsernameChange => {
+                            let mut s = state.lock().await;
+                            let result = match validate_name(&packet.contents) {
+                                Ok(name) if name == local.name => Ok(None),
+                                Ok(_) if last_name_change.is_some_and(|last| last.elapsed() < NAME_CHANGE_INTERVAL) => {
+                                    Err("You can only change your name once every 10 seconds")
+                                },
+                                Ok(name) if s.name_taken(local.uid, &name) => Err("That name is already taken"),
+                                Ok(name) => Ok(Some(name)),
+                                Err(reason) => Err(reason),
+                            };
+
+                            match result {
+                                Ok(Some(name)) => {
+                                    let old_name = std::mem::replace(&mut local.name, name);
+                                    Span::current().record("name", local.name.as_str());
+                                    last_name_change = Some(Instant::now());
+
+                                    let user = s.user_list.get_mut(&local.uid).unwrap();
+                                    user.name = local.name.clone();
+                                    user.previous_names.retain(|previous| *previous != old_name);
+                                    user.previous_names.push(old_name);
+                                    if user.previous_names.len() > NAME_HISTORY_LEN {
+                                        user.previous_names.remove(0);
+                                    }
+
+                                    packet.contents = local.name.clone();
+                                    true
+                                },
+                                // Nothing changed, so there's nothing to announce
+                                Ok(None) => false,
+                                Err(reason) => {
+                                    drop(s);
+                                    write_packet(&mut writer, &Packet::error(reason)).await?;
+                                    false
+                                },
+                            }
+                        },
+                        PacketType::NewMessage | PacketType::EditMessage | PacketType::PrivateMessage
+                            if packet.contents.trim().chars().count() > MAX_MESSAGE_LENGTH =>
                         {
+                            let reason = format!("Messages must be at most {} characters", MAX_MESSAGE_LENGTH);
+                            write_packet(&mut writer, &Packet::error(reason)).await?;
+                            false
+                        },
+                        PacketType::NewMessage => {
+                            // Talking means the user is back
+                            if local.away.is_some() {
+                                set_away(&state, &sender, &mut local, None).await;
+                            }
+
+                            let mut message = Message {
+                                sender_id: local.uid,
+                                sender_name: local.name.clone(),
+                                message: config.filter_words(&config.expand_shortcodes(packet.contents.trim())),
+                                timestamp: unix_time(),
+                                action: packet.message.as_ref().is_some_and(|message| message.action),
+                                reply_to: packet.message.as_ref().and_then(|message| message.reply_to),
+                                ..Default::default()
+                            };
+                            {
+                                let mut s = state.lock().await;
+                                message.uid = s.new_message_id();
+                                s.messages_sent += 1;
+                                if let Some(user) = s.user_list.get_mut(&local.uid) {
+                                    user.messages.push(message.uid);
+                                }
+                                s.store_message(&local.room, message.clone(), config.history_limit);
+                                s.mark_dirty();
+                            }
+
+                            // Confirm to the sender before anyone else sees it
+                            let ack = Packet {
+                                packet_type: PacketType::Ack,
+                                message: Some(message.clone()),
+                                nonce: packet.nonce,
+                                ..Default::default()
+                            };
+                            write_packet(&mut writer, &ack).await?;
+
+                            packet.contents = message.message.clone();
+                            packet.room = local.room.clone();
+                            packet.message = Some(message);
+                            packet.nonce = 0;
+                            true
+                        },
+                        PacketType::EditMessage => {
+                            let uid = packet.message.as_ref().map_or(0, |message| message.uid);
+                            let text = config.filter_words(&config.expand_shortcodes(packet.contents.trim()));
+                            let edited = {
+                                let mut s = state.lock().await;
+                                match s.find_message_mut(uid) {
+                                    Some((room, message)) if message.sender_id == local.uid => {
+                                        message.message = text;
+                                        message.edited = true;
+                                        packet.room = room;
+                                        packet.contents = message.message.clone();
+                                        packet.message = Some(message.clone());
+                                        s.mark_dirty();
+                                        Ok(())
+                                    },
+                                    Some(_) => Err("You can only edit your own messages"),
+                                    None => Err("No such message"),
+                                }
+                            };
+                            match edited {
+                                Ok(()) => true,
+                                Err(reason) => {
+                                    write_packet(&mut writer, &Packet::error(reason)).await?;
+                                    false
+                                },
+                            }
+                        },
+                        PacketType::DeleteMessage => {
+                            let uid = packet.message.as_ref().map_or(0, |message| message.uid);
+                            let deleted = {
+                                let mut s = state.lock().await;
+                                match s.find_message_mut(uid) {
+                                    Some((room, message)) if message.sender_id == local.uid => {
+                                        if let Some(messages) = s.rooms.get_mut(&room) {
+                                            messages.retain(|message| message.uid != uid);
+                                        }
+                                        packet.room = room;
+                                        s.mark_dirty();
+                                        Ok(())
+                                    },
+                                    Some(_) => Err("You can only delete your own messages"),
+                                    None => Err("No such message"),
+                                }
+                            };
+                            match deleted {
+                                Ok(()) => true,
+                                Err(reason) => {
+                                    write_packet(&mut writer, &Packet::error(reason)).await?;
+                                    false
+                                },
+                            }
+                        },
+                        PacketType::DiceRoll => {
+                            match roll_dice(&packet.contents) {
+                                Ok(rolls) => {
+                                    let total: u32 = rolls.iter().sum();
+                                    let rolls: Vec<String> = rolls.iter().map(u32::to_string).collect();
+                                    packet.room = local.room.clone();
+                                    packet.contents = format!(
+                                        "{} rolled {}: {} (total {})",
+                                        local.name,
+                                        packet.contents.trim().to_lowercase(),
+                                        rolls.join(", "),
+                                        total,
+                                    );
+                                    true
+                                },
+                                Err(reason) => {
+                                    write_packet(&mut writer, &Packet::error(reason)).await?;
+                                    false
+                                },
+                            }
+                        },
+                        PacketType::HistoryRequest => {
+                            let before = packet.message.as_ref().map_or(0, |message| message.uid);
+                            let limit = packet.limit.clamp(1, MAX_HISTORY_PAGE);
+                            let (mut history, start, trimmed) = {
+                                let s = state.lock().await;
+                                let (history, start) = s.history_page(&local.room, before, limit as usize);
+                                (history, start, s.trimmed_rooms.contains(&local.room))
+                            };
+
+                            // A page cut short says it was full, so the
+                            // client asks for the rest
+                            let (limit, start) = match fit_page(&mut history) {
+                                true => (history.len() as u32, false),
+                                false => (limit, start),
+                            };
+                            let page = Packet {
+                                packet_type: PacketType::HistoryRequest,
+                                room: local.room.clone(),
+                                contents: if start && trimmed { String::from(TRIMMED_NOTICE) } else { String::new() },
+                                limit,
+                                history,
+                                ..Default::default()
+                            };
+                            write_packet(&mut writer, &page).await?;
+                            false
+                        },
+                        // Answered for the user in target, or the sender if
+                        // none is given
+                        PacketType::Stats => {
+                            let uid = if packet.target == 0 { local.uid } else { packet.target };
+                            let stats = state.lock().await.user_list.get(&uid).map(|user| Packet {
+                                packet_type: PacketType::Stats,
+                                contents: user.name.clone(),
+                                target: user.uid,
+                                count: user.messages.len() as u32,
+                                address: match user.address {
+                                    Some(address) if local.is_admin => address.to_string(),
+                                    _ => String::new(),
+                                },
+                                ..Default::default()
+                            });
+                            match stats {
+                                Some(stats) => write_packet(&mut writer, &stats).await?,
+                                None => write_packet(&mut writer, &Packet::error("No such user")).await?,
+                            }
+                            false
+                        },
+                        PacketType::WhoisRequest => {
+                            let name = packet.contents.trim();
+                            let whois = state.lock().await.user_list
+                                .values()
+                                .find(|user| user.name.eq_ignore_ascii_case(name))
+                                .map(|user| Packet {
+                                    packet_type: PacketType::WhoisRequest,
+                                    contents: user.name.clone(),
+                                    target: user.uid,
+                                    count: user.messages.len() as u32,
+                                    connected_at: user.connected_at,
+                                    previous_names: user.previous_names.clone(),
+                                    address: match user.address {
+                                        Some(address) if local.is_admin => address.to_string(),
+                                        _ => String::new(),
+                                    },
+                                    ..Default::default()
+                                });
+                            match whois {
+                                Some(whois) => write_packet(&mut writer, &whois).await?,
+                                None => write_packet(&mut writer, &Packet::error("No such user")).await?,
+                            }
+                            false
+                        },
+                        // Answered with how many messages matched, then the
+                        // newest of them in a packet for each room
+                        PacketType::Grep => {
+                            let query = packet.contents.trim().to_lowercase();
+                            let found = match () {
+                                _ if !local.is_admin => Err("Only admins can search the history"),
+                                _ if query.is_empty() => Err("Nothing to search for"),
+                                _ => Ok(state.lock().await.grep(&query, MAX_GREP_RESULTS)),
+                            };
+                            match found {
+                                Ok((rooms, total)) => {
+                                    let summary = Packet {
+                                        packet_type: PacketType::Grep,
+                                        contents: query.clone(),
+                                        count: total as u32,
+                                        limit: rooms.values().map(Vec::len).sum::<usize>() as u32,
+                                        ..Default::default()
+                                    };
+                                    write_packet(&mut writer, &summary).await?;
+                                    for (room, history) in rooms {
+                                        let results = Packet {
+                                            packet_type: PacketType::Grep,
+                                            contents: query.clone(),
+                                            room,
+                                            history,
+                                            ..Default::default()
+                                        };
+                                        write_packet(&mut writer, &results).await?;
+                                    }
+                                },
+                                Err(reason) => {
+                                    write_packet(&mut writer, &Packet::error(reason)).await?;
+                                },
+                            }
+                            false
+                        },
+                        PacketType::ReadReceipt => {
+                            let uid = packet.message.as_ref().map_or(0, |message| message.uid);
                             let mut s = state.lock().await;
-                            s.message_list.push(message.clone());
-                        }
-                    },
-                    _ => {
-                        println!("[SERVER] Unknown packet received");
-                    },
+                            match s.add_receipt(&local.room, uid, local.uid) {
+                                Some(readers) => {
+                                    packet.room = local.room.clone();
+                                    packet.readers = readers;
+                                    true
+                                },
+                                None => false,
+                            }
+                        },
+                        PacketType::PrivateMessage => {
+                            if local.away.is_some() {
+                                set_away(&state, &sender, &mut local, None).await;
+                            }
+
+                            let uid = {
+                                let mut s = state.lock().await;
+                                s.messages_sent += 1;
+                                s.new_message_id()
+                            };
+                            let message = Message {
+                                uid,
+                                sender_id: local.uid,
+                                sender_name: local.name.clone(),
+                                message: config.expand_shortcodes(packet.contents.trim()),
+                                timestamp: unix_time(),
+                                ..Default::default()
+                            };
+                            let whisper = Packet {
+                                packet_type: PacketType::PrivateMessage,
+                                user_id: local.uid,
+                                contents: message.message.clone(),
+                                target: packet.target,
+                                message: Some(message),
+                                ..Default::default()
+                            };
+
+                            // Away users answer whispers with their reason
+                            let (recipient, auto_reply) = {
+                                let mut s = state.lock().await;
+                                let away = s.user_list.get(&packet.target)
+                                    .filter(|user| user.uid != local.uid)
+                                    .and_then(|user| Some((user.uid, user.name.clone(), user.away.clone()?)));
+                                let auto_reply = away.map(|(uid, name, reason)| Packet {
+                                    packet_type: PacketType::PrivateMessage,
+                                    user_id: uid,
+                                    contents: format!("(auto-reply) I'm away: {}", reason),
+                                    target: local.uid,
+                                    message: Some(Message {
+                                        uid: s.new_message_id(),
+                                        sender_id: uid,
+                                        sender_name: name,
+                                        message: format!("(auto-reply) I'm away: {}", reason),
+                                        timestamp: unix_time(),
+                                        ..Default::default()
+                                    }),
+                                    ..Default::default()
+                                });
+                                (s.connections.get(&packet.target).cloned(), auto_reply)
+                            };
+
+                            // Deliver to the recipient only, then echo to the sender
+                            match recipient {
+                                Some(recipient) => {
+                                    if packet.target != local.uid {
+                                        let _ = recipient.send(whisper.clone());
+                                    }
+                                    write_packet(&mut writer, &whisper).await?;
+                                    if let Some(auto_reply) = auto_reply {
+                                        write_packet(&mut writer, &auto_reply).await?;
+                                    }
+                                },
+                                None => {
+                                    write_packet(&mut writer, &Packet::error("No such user")).await?;
+                                },
+                            }
+                            false
+                        },
+                        PacketType::ColorChange => {
+                            match validate_color(&packet.contents) {
+                                Ok(color) => {
+                                    if let Some(user) = state.lock().await.user_list.get_mut(&local.uid) {
+                                        user.color = color.clone();
+                                    }
+                                    local.color = color;
+                                    packet.contents = local.color.clone().unwrap_or_default();
+                                    true
+                                },
+                                Err(reason) => {
+                                    write_packet(&mut writer, &Packet::error(reason)).await?;
+                                    false
+                                },
+                            }
+                        },
+                        PacketType::StatusChange => {
+                            let reason = packet.contents.trim();
+                            let away = (!reason.is_empty()).then(|| reason.to_string());
+                            set_away(&state, &sender, &mut local, away).await;
+                            false
+                        },
+                        PacketType::Kick => {
+                            let target = {
+                                let s = state.lock().await;
+                                match s.user_list.get(&packet.target) {
+                                    _ if !local.is_admin => Err("Only admins can kick users"),
+                                    Some(user) if user.uid == local.uid => Err("You can't kick yourself"),
+                                    Some(user) => Ok((user.name.clone(), s.connections.get(&user.uid).cloned())),
+                                    None => Err("No such user"),
+                                }
+                            };
+                            match target {
+                                Ok((name, connection)) => {
+                                    let reason = packet.contents.trim();
+                                    let reason = if reason.is_empty() { String::new() } else { format!(": {}", reason) };
+                                    info!(kicked = %name, "Kicking user");
+
+                                    // Their own task closes the connection once they are told
+                                    if let Some(connection) = connection {
+                                        let _ = connection.send(Packet {
+                                            packet_type: PacketType::Kick,
+                                            user_id: packet.target,
+                                            contents: format!("You were kicked by {}{}", local.name, reason),
+                                            ..Default::default()
+                                        });
+                                    }
+
+                                    packet.user_id = packet.target;
+                                    packet.contents = format!("{} was kicked by {}{}", name, local.name, reason);
+                                    true
+                                },
+                                Err(reason) => {
+                                    write_packet(&mut writer, &Packet::error(reason)).await?;
+                                    false
+                                },
+                            }
+                        },
+                        // File transfers are passed straight to the recipient,
+                        // which checks the chunks add up
+                        PacketType::FileStart | PacketType::FileChunk | PacketType::FileEnd => {
+                            let size = packet.file.as_ref().map(|file| file.size);
+                            let recipient = state.lock().await.connections
+                                .get(&packet.target)
+                                .filter(|_| packet.target != local.uid)
+                                .cloned();
+                            match recipient {
+                                _ if size.is_none() => (),
+                                Some(_) if packet.packet_type == PacketType::FileStart && size.is_some_and(|size| size > MAX_FILE_SIZE) => {
+                                    write_packet(&mut writer, &Packet::error("Files can be at most 10 MB")).await?;
+                                },
+                                Some(recipient) => {
+                                    let _ = recipient.send(packet.clone());
+                                },
+                                None if packet.packet_type == PacketType::FileStart => {
+                                    write_packet(&mut writer, &Packet::error("No such user")).await?;
+                                },
+                                // The recipient left partway through
+                                None => (),
+                            }
+                            false
+                        },
+                        PacketType::Typing | PacketType::StoppedTyping => {
+                            packet.room = local.room.clone();
+                            true
+                        },
+                        PacketType::Pong => {
+                            pong_deadline = None;
+                            false
+                        },
+                        // Clients time the answer to measure their latency
+                        PacketType::Ping => {
+                            let pong = Packet {
+                                packet_type: PacketType::Pong,
+                                contents: packet.contents.clone(),
+                                ..Default::default()
+                            };
+                            write_packet(&mut writer, &pong).await?;
+                            false
+                        },
+                        // The client is leaving on purpose
+                        PacketType::UserDisconnected => {
+                            parting_message = config.filter_words(packet.contents.trim());
+                            return Ok(());
+                        },
+                        PacketType::JoinRoom => {
+                            let room = packet.room.trim().trim_start_matches('#');
+                            if !room.is_empty() {
+                                join_room(&mut writer, &state, &mut local, room).await?;
+                            }
+                            false
+                        },
+                        _ => {
+                            warn!(packet_type = ?packet.packet_type, "Unknown packet received");
+                            false
+                        },
+                    };
+
+                    if broadcast {
+                        send_to_all(&sender, packet);
+                    }
                 }
 
-                // Redirect packet to broadcast channel
-                let _ = sender.send(packet_clone);
-            }
+                // Ping the client to check the connection is still alive
+                _ = heartbeat.tick() => {
+                    let ping = Packet {
+                        packet_type: PacketType::Ping,
+                        ..Default::default()
+                    };
+                    write_packet(&mut writer, &ping).await?;
+                    if pong_deadline.is_none() {
+                        pong_deadline = Some(Instant::now() + PONG_TIMEOUT);
+                    }
+                }
+
+                // Drop clients that stopped answering pings
+                _ = time::sleep_until(pong_deadline.unwrap_or_else(Instant::now)), if pong_deadline.is_some() => {
+                    info!("Client timed out");
+                    return Ok(());
+                }
 
-            // Send data from broadcast channel to client
-            channel_read_result = receiver.recv() => {
-                if let Ok(packet) = channel_read_result {
-                    if packet.user_id != local.uid ||
-                        packet.packet_type == PacketType::NewMessage ||
-                        packet.packet_type == PacketType::UsernameChange
-                    {
-                        let data = serde_json::to_string(&packet).unwrap();
-                        let _ = writer.write(data.as_bytes()).await?;
-                        writer.flush().await?;
+                // Drop clients that have been idle for too long
+                _ = time::sleep_until(last_activity + config.idle_timeout.unwrap_or_default()), if config.idle_timeout.is_some() => {
+                    let notice = Packet {
+                        packet_type: PacketType::IdleKick,
+                        contents: String::from("You were disconnected for being idle too long"),
+                        ..Default::default()
+                    };
+                    write_packet(&mut writer, &notice).await?;
+                    info!("Client was idle for too long");
+                    return Ok(());
+                }
+
+                // Send packets addressed only to this client
+                Some(packet) = direct.recv() => {
+                    write_packet(&mut writer, &packet).await?;
+                    if packet.packet_type == PacketType::Kick {
+                        info!("Client was kicked");
+                        return Ok(());
+                    }
+                }
+
+                // Send data from broadcast channel to client
+                channel_read_result = receiver.recv() => {
+                    match channel_read_result {
+                        Ok(packet) => {
+                            let forward = match packet.packet_type {
+                                // Chat messages only reach clients in the same room
+                                PacketType::NewMessage |
+                                PacketType::EditMessage |
+                                PacketType::DeleteMessage |
+                                PacketType::ReadReceipt |
+                                PacketType::DiceRoll => packet.room == local.room,
+                                PacketType::Typing | PacketType::StoppedTyping => {
+                                    packet.room == local.room && packet.user_id != local.uid
+                                },
+                                PacketType::UsernameChange | PacketType::StatusChange | PacketType::ColorChange => true,
+                                _ => packet.user_id != local.uid,
+                            };
+                            if forward {
+                                write_packet(&mut writer, &packet).await?;
+                            }
+                        },
+                        // The client fell too far behind and packets were
+                        // dropped. Resend the room so it doesn't miss messages.
+                        Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                            warn!(skipped, "Client lagged behind, resyncing");
+                            let room = local.room.clone();
+                            join_room(&mut writer, &state, &mut local, &room).await?;
+                        },
+                        Err(broadcast::error::RecvError::Closed) => return Ok(()),
                     }
-                } 
+                }
             }
         }
-    }
+    }.await;
+    reader_task.abort();
 
     // Remove user from list
     let mut s = state.lock().await;
     s.user_list.remove(&local.uid);
+    s.connections.remove(&local.uid);
 
     // Broadcast Disconnect Packet
     let packet = Packet {
         packet_type: PacketType::UserDisconnected,
         user_id: local.uid,
-        contents: String::new(),
+        contents: parting_message,
+        ..Default::default()
     };
-    let _ = sender.send(packet);
-    
-    Ok(())
+    send_to_all(&sender, packet);
+
+    result
+}
+
+// A plaintext client never starts the handshake, so give up after a while
+// instead of waiting forever
+async fn accept_tls(acceptor: &TlsAcceptor, stream: TcpStream) -> io::Result<TlsStream<TcpStream>> {
+    match time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+        Ok(result) => result,
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "TLS handshake timed out, the client may not be using TLS",
+        )),
+    }
+}
+
+// Tell a client there is no room for them and hang up
+async fn reject_full<S: AsyncWrite + Unpin>(stream: S) -> io::Result<()> {
+    let packet = Packet {
+        packet_type: PacketType::ServerFull,
+        contents: String::from("Server is full, try again later"),
+        ..Default::default()
+    };
+    let mut writer = PacketWriter::new(stream);
+    write_packet(&mut writer, &packet).await?;
+    writer.into_inner().shutdown().await
+}
+
+// Verbosity comes from RUST_LOG, e.g. RUST_LOG=debug to see every packet
+fn init_logging(json: bool) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter);
+    if json {
+        subscriber.json().init();
+    }
+    else {
+        subscriber.init();
+    }
 }
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
+    let config = Arc::new(Config::from_args()?);
+    init_logging(config.log_json);
     let state: Arc<Mutex<ServerState>> = Arc::new(Mutex::new(ServerState::default()));
 
+    // Restore history and keep the data file up to date
+    let stop_saving = Arc::new(Notify::new());
+    let saver = if let Some(path) = &config.data_file {
+        let snapshot = persist::load(path)?;
+        {
+            // Carry on numbering messages where the last run left off
+            let mut s = state.lock().await;
+            s.last_message_id = snapshot.rooms.values()
+                .flatten()
+                .map(|message| message.uid)
+                .chain([snapshot.last_message_id])
+                .max()
+                .unwrap_or_default();
+            s.rooms = snapshot.rooms;
+            s.trimmed_rooms = snapshot.trimmed_rooms;
+
+            // The limit may have been lowered since the history was saved
+            let rooms: Vec<String> = s.rooms.keys().cloned().collect();
+            for room in rooms {
+                s.trim_room(&room, config.history_limit);
+            }
+        }
+        Some(tokio::spawn(persist::run(path.clone(), state.clone(), stop_saving.clone())))
+    }
+    else {
+        None
+    };
+
     // Create listener
-    let listener = TcpListener::bind("127.0.0.1:8080")
+    let listener = TcpListener::bind(&config.bind)
         .await
-        .expect("Error: Failed to bind to port");
-    println!("Server listening on port 8080");
+        .map_err(|error| io::Error::new(error.kind(), format!("Failed to bind to {}: {}", config.bind, error)))?;
+    info!(address = %listener.local_addr()?, tls = config.tls.is_some(), "Server listening");
+
+    // Counters for operators, off unless asked for. They are served on the
+    // same address as the chat.
+    if let Some(port) = config.metrics_port {
+        let address = SocketAddr::new(listener.local_addr()?.ip(), port);
+        let metrics_listener = TcpListener::bind(address).await?;
+        info!(%address, "Serving metrics");
+        tokio::spawn(metrics::run(metrics_listener, state.clone()));
+    }
 
     // Create broadcast channel
-    let (channel, _) = broadcast::channel::<Packet>(10);
+    let (channel, _) = broadcast::channel::<Packet>(config.channel_capacity);
+
+    // Each connected client holds a permit until its task finishes
+    let connections = Arc::new(Semaphore::new(config.max_connections));
+
+    // Connections each address may open per window, unlimited when unset
+    let mut connect_limiter = config.connect_limit.map(ConnectLimiter::new);
 
-    // Server Loop. Listen for new connections
+    // Server Loop. Listen for new connections until Ctrl+C
+    let mut clients = JoinSet::new();
+    let shutdown = signal::ctrl_c();
+    tokio::pin!(shutdown);
     loop {
-        // Accept connection
-        let (client_stream, _) = listener.accept().await?;
-        println!("[SERVER] Connected Received");
-
-        // Create task to handle connection
-        let channel_clone = channel.clone();
-        let state_clone = state.clone();
-        tokio::spawn(async move {
-            match handle_client(client_stream, channel_clone, state_clone).await {
-                Ok(_) => println!("[SERVER] Client Disconnected"),
-                Err(error) => println!("[ERROR] Failed to handle connection: {}", error)
+        tokio::select! {
+            accept_result = listener.accept() => {
+                // Accept connection
+                let (client_stream, address) = accept_result?;
+                if let Some(limiter) = &mut connect_limiter
+                    && !limiter.try_connect(address.ip(), Instant::now())
+                {
+                    warn!(%address, limit = limiter.limit, "Too many connections from address, refusing");
+                    continue;
+                }
+                info!(%address, "Connection received");
+                let span = info_span!("client", %address, uid = field::Empty, name = field::Empty);
+
+                let Ok(permit) = connections.clone().try_acquire_owned() else {
+                    warn!(%address, limit = config.max_connections, "Connection limit reached, rejecting client");
+                    let acceptor = config.tls.clone();
+                    clients.spawn(async move {
+                        let rejected = async {
+                            match acceptor {
+                                Some(acceptor) => reject_full(accept_tls(&acceptor, client_stream).await?).await,
+                                None => reject_full(client_stream).await,
+                            }
+                        };
+                        let _ = time::timeout(REJECT_TIMEOUT, rejected).await;
+                    }.instrument(span));
+                    continue;
+                };
+
+                // Create task to handle connection
+                let channel_clone = channel.clone();
+                let state_clone = state.clone();
+                let config_clone = config.clone();
+                clients.spawn(async move {
+                    let result = match config_clone.tls.clone() {
+                        Some(acceptor) => match accept_tls(&acceptor, client_stream).await {
+                            Ok(tls_stream) => {
+                                handle_client(tls_stream, address, channel_clone, state_clone, config_clone).await
+                            },
+                            Err(error) => Err(error),
+                        },
+                        None => handle_client(client_stream, address, channel_clone, state_clone, config_clone).await,
+                    };
+                    match result {
+                        Ok(_) => info!("Client disconnected"),
+                        Err(error) => warn!(%error, "Failed to handle connection"),
+                    };
+
+                    // Free the slot for the next client
+                    drop(permit);
+                }.instrument(span));
+            }
+
+            // Clean up tasks of clients that have left
+            Some(_) = clients.join_next() => (),
+
+            _ = &mut shutdown => break,
+        }
+    }
+
+    // Stop accepting, tell every client, then save what they sent
+    info!("Shutting down");
+    drop(listener);
+    let shutdown_packet = Packet {
+        packet_type: PacketType::ServerShutdown,
+        ..Default::default()
+    };
+    send_to_all(&channel, shutdown_packet);
+    let all_disconnected = async {
+        while clients.join_next().await.is_some() {}
+    };
+    if time::timeout(SHUTDOWN_TIMEOUT, all_disconnected).await.is_err() {
+        warn!("Timed out waiting for clients to disconnect");
+    }
+
+    // The saver writes out everything before it finishes
+    if let Some(saver) = saver {
+        stop_saving.notify_one();
+        saver.await??;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{DuplexStream, ReadHalf, WriteHalf, duplex};
+
+    // Reads past everything else the server sends until a packet of this type
+    async fn expect<R: AsyncRead + Unpin>(reader: &mut R, packet_type: PacketType) -> Packet {
+        loop {
+            let packet = time::timeout(Duration::from_secs(5), read_packet(reader))
+                .await
+                .expect("Timed out waiting for a packet")
+                .unwrap();
+            if packet.packet_type == packet_type {
+                return packet;
+            }
+        }
+    }
+
+    // Connects a client to a fresh server and logs in, returning its uid
+    async fn join(name: &str) -> (u32, ReadHalf<DuplexStream>, PacketWriter<WriteHalf<DuplexStream>>) {
+        let state = Arc::new(Mutex::new(ServerState::default()));
+        join_with(name, state, Config::default()).await
+    }
+
+    // Same as join, on a server with the given state and settings
+    async fn join_with(
+        name: &str,
+        state: Arc<Mutex<ServerState>>,
+        config: Config,
+    ) -> (u32, ReadHalf<DuplexStream>, PacketWriter<WriteHalf<DuplexStream>>) {
+        let (client, server) = duplex(64 * 1024);
+        let (sender, _) = broadcast::channel(16);
+        let address = "127.0.0.1:50000".parse().unwrap();
+        tokio::spawn(handle_client(server, address, sender, state, Arc::new(config)));
+
+        let (mut reader, writer) = tokio_io::split(client);
+        let mut writer = PacketWriter::new(writer);
+        let uid = expect(&mut reader, PacketType::IDAssign).await.user_id;
+        let login = Packet {
+            packet_type: PacketType::UsernameChange,
+            user_id: uid,
+            contents: name.to_string(),
+            version: PROTOCOL_VERSION,
+            ..Default::default()
+        };
+        write_packet(&mut writer, &login).await.unwrap();
+        (uid, reader, writer)
+    }
+
+    #[tokio::test]
+    async fn clients_on_another_protocol_version_are_turned_away() {
+        let (client, server) = duplex(64 * 1024);
+        let (sender, _) = broadcast::channel(16);
+        let state = Arc::new(Mutex::new(ServerState::default()));
+        let address = "127.0.0.1:50000".parse().unwrap();
+        let handle = tokio::spawn(handle_client(server, address, sender, state, Arc::new(Config::default())));
+
+        let (mut reader, writer) = tokio_io::split(client);
+        let mut writer = PacketWriter::new(writer);
+        let uid = expect(&mut reader, PacketType::IDAssign).await.user_id;
+        let login = Packet {
+            packet_type: PacketType::UsernameChange,
+            user_id: uid,
+            contents: String::from("alice"),
+            version: PROTOCOL_VERSION - 1,
+            ..Default::default()
+        };
+        write_packet(&mut writer, &login).await.unwrap();
+
+        let mismatch = expect(&mut reader, PacketType::VersionMismatch).await;
+        assert_eq!(mismatch.version, PROTOCOL_VERSION);
+        let error = handle.await.unwrap().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn lone_client_sees_its_own_echoes() {
+        let (uid, mut reader, mut writer) = join("alice").await;
+
+        let message = Packet {
+            packet_type: PacketType::NewMessage,
+            user_id: uid,
+            contents: String::from("hello"),
+            ..Default::default()
+        };
+        write_packet(&mut writer, &message).await.unwrap();
+        let echo = expect(&mut reader, PacketType::NewMessage).await;
+        assert_eq!((echo.user_id, echo.contents.as_str()), (uid, "hello"));
+
+        let rename = Packet {
+            packet_type: PacketType::UsernameChange,
+            user_id: uid,
+            contents: String::from("bob"),
+            ..Default::default()
+        };
+        write_packet(&mut writer, &rename).await.unwrap();
+        let echo = expect(&mut reader, PacketType::UsernameChange).await;
+        assert_eq!((echo.user_id, echo.contents.as_str()), (uid, "bob"));
+    }
+
+    #[tokio::test]
+    async fn shortcodes_are_expanded_before_messages_are_stored_and_sent() {
+        let state = Arc::new(Mutex::new(ServerState::default()));
+        let config = Config {
+            shortcodes: Some(HashMap::from([(String::from("wave"), String::from("👋"))])),
+            ..Default::default()
+        };
+        let (uid, mut reader, mut writer) = join_with("alice", state.clone(), config).await;
+
+        let message = Packet {
+            packet_type: PacketType::NewMessage,
+            user_id: uid,
+            contents: String::from("hi :wave: :nope:"),
+            ..Default::default()
+        };
+        write_packet(&mut writer, &message).await.unwrap();
+        let echo = expect(&mut reader, PacketType::NewMessage).await;
+        assert_eq!(echo.contents, "hi 👋 :nope:");
+        assert_eq!(echo.message.map(|message| message.message).as_deref(), Some("hi 👋 :nope:"));
+
+        let s = state.lock().await;
+        let stored: Vec<&str> = s.rooms[DEFAULT_ROOM].iter().map(|message| message.message.as_str()).collect();
+        assert_eq!(stored, ["hi 👋 :nope:"]);
+    }
+
+    #[tokio::test]
+    async fn away_auto_replies_are_not_counted_as_sent() {
+        let state = Arc::new(Mutex::new(ServerState::default()));
+        let (alice, mut reader, mut writer) = join_with("alice", state.clone(), Config::default()).await;
+        let (bob, mut bob_reader, _bob_writer) = join_with("bob", state.clone(), Config::default()).await;
+        expect(&mut reader, PacketType::JoinRoom).await;
+        expect(&mut bob_reader, PacketType::JoinRoom).await;
+        state.lock().await.user_list.get_mut(&bob).unwrap().away = Some(String::from("lunch"));
+
+        let whisper = Packet {
+            packet_type: PacketType::PrivateMessage,
+            user_id: alice,
+            contents: String::from("hi"),
+            target: bob,
+            ..Default::default()
+        };
+        write_packet(&mut writer, &whisper).await.unwrap();
+        assert_eq!(expect(&mut reader, PacketType::PrivateMessage).await.user_id, alice);
+        let auto_reply = expect(&mut reader, PacketType::PrivateMessage).await;
+        assert_eq!(auto_reply.user_id, bob);
+
+        assert_eq!(state.lock().await.messages_sent, 1);
+    }
+
+    #[tokio::test]
+    async fn whois_shows_former_names() {
+        let (uid, mut reader, mut writer) = join("alice").await;
+        let rename = Packet {
+            packet_type: PacketType::UsernameChange,
+            user_id: uid,
+            contents: String::from("bob"),
+            ..Default::default()
+        };
+        write_packet(&mut writer, &rename).await.unwrap();
+        expect(&mut reader, PacketType::UsernameChange).await;
+
+        let whois = Packet {
+            packet_type: PacketType::WhoisRequest,
+            user_id: uid,
+            contents: String::from("bob"),
+            ..Default::default()
+        };
+        write_packet(&mut writer, &whois).await.unwrap();
+        let answer = expect(&mut reader, PacketType::WhoisRequest).await;
+        assert_eq!(answer.previous_names, ["alice"]);
+    }
+
+    #[tokio::test]
+    async fn pages_of_long_messages_arrive_in_full() {
+        // Too many bytes for one packet once they are all in a page
+        let text = |uid: u32| format!("{} {}", uid, "€".repeat(MAX_MESSAGE_LENGTH - 4));
+        let state = Arc::new(Mutex::new(ServerState::default()));
+        {
+            let mut s = state.lock().await;
+            for _ in 0..MAX_HISTORY_PAGE {
+                let uid = s.new_message_id();
+                let message = Message {
+                    uid,
+                    message: text(uid),
+                    ..Default::default()
+                };
+                s.store_message(DEFAULT_ROOM, message, None);
+            }
+        }
+        let (uid, mut reader, mut writer) = join_with("alice", state, Config::default()).await;
+
+        let mut received = Vec::new();
+        let mut before = 0;
+        let mut pages = 0;
+        loop {
+            let request = Packet {
+                packet_type: PacketType::HistoryRequest,
+                user_id: uid,
+                message: Some(Message {
+                    uid: before,
+                    ..Default::default()
+                }),
+                limit: MAX_HISTORY_PAGE,
+                ..Default::default()
             };
-        });
+            write_packet(&mut writer, &request).await.unwrap();
+            let page = expect(&mut reader, PacketType::HistoryRequest).await;
+            pages += 1;
+            received.splice(0..0, page.history.iter().cloned());
+            if page.history.len() < page.limit as usize {
+                break;
+            }
+            before = page.history[0].uid;
+        }
+
+        assert!(pages > 1);
+        assert_eq!(received.len(), MAX_HISTORY_PAGE as usize);
+        for (index, message) in received.iter().enumerate() {
+            assert_eq!(message.uid, index as u32 + 1);
+            assert_eq!(message.message, text(message.uid));
+        }
+
+        // Longer messages are refused outright
+        let message = Packet {
+            packet_type: PacketType::NewMessage,
+            user_id: uid,
+            contents: "x".repeat(MAX_MESSAGE_LENGTH + 1),
+            ..Default::default()
+        };
+        write_packet(&mut writer, &message).await.unwrap();
+        expect(&mut reader, PacketType::Error).await;
+    }
+
+    #[tokio::test]
+    async fn spoofed_user_ids_are_ignored() {
+        let (uid, mut reader, mut writer) = join("mallory").await;
+        let victim = uid.wrapping_add(1);
+
+        let message = Packet {
+            packet_type: PacketType::NewMessage,
+            user_id: victim,
+            contents: String::from("hello"),
+            ..Default::default()
+        };
+        write_packet(&mut writer, &message).await.unwrap();
+        let echo = expect(&mut reader, PacketType::NewMessage).await;
+        assert_eq!(echo.user_id, uid);
+        assert_eq!(echo.message.map(|message| message.sender_id), Some(uid));
+
+        let rename = Packet {
+            packet_type: PacketType::UsernameChange,
+            user_id: victim,
+            contents: String::from("alice"),
+            ..Default::default()
+        };
+        write_packet(&mut writer, &rename).await.unwrap();
+        let echo = expect(&mut reader, PacketType::UsernameChange).await;
+        assert_eq!((echo.user_id, echo.contents.as_str()), (uid, "alice"));
+    }
+
+    #[test]
+    fn unique_names_stay_within_the_length_limit() {
+        let mut state = ServerState::default();
+        let name = "é".repeat(MAX_NAME_LENGTH);
+        for uid in 1..=2 {
+            let user = User {
+                uid,
+                name: state.unique_name(uid, &name),
+                ..Default::default()
+            };
+            state.user_list.insert(uid, user);
+        }
+
+        let renamed = state.unique_name(3, &name);
+        assert_eq!(renamed, format!("{}#3", "é".repeat(MAX_NAME_LENGTH - 2)));
+        assert_eq!(state.user_list[&2].name, format!("{}#2", "é".repeat(MAX_NAME_LENGTH - 2)));
+        assert!(validate_name(&renamed).is_ok());
+    }
+
+    #[test]
+    fn colors_are_names_or_hex_codes() {
+        assert_eq!(validate_color(" LightBlue "), Ok(Some(String::from("lightblue"))));
+        assert_eq!(validate_color("#FF8800"), Ok(Some(String::from("#ff8800"))));
+        assert_eq!(validate_color(""), Ok(None));
+        for color in ["black", "#ff88", "#gg8800", "ff8800"] {
+            assert!(validate_color(color).is_err());
+        }
+    }
+
+    #[test]
+    fn connections_are_limited_per_address() {
+        let mut limiter = ConnectLimiter::new(2);
+        let flooder: IpAddr = "10.0.0.1".parse().unwrap();
+        let other: IpAddr = "::1".parse().unwrap();
+        let start = Instant::now();
+
+        assert!(limiter.try_connect(flooder, start));
+        assert!(limiter.try_connect(flooder, start + Duration::from_secs(30)));
+        assert!(!limiter.try_connect(flooder, start + Duration::from_secs(31)));
+        assert!(limiter.try_connect(other, start + Duration::from_secs(31)));
+
+        // The first connection has left the window, the second hasn't
+        assert!(limiter.try_connect(flooder, start + CONNECT_WINDOW));
+        assert!(!limiter.try_connect(flooder, start + CONNECT_WINDOW));
+    }
+
+    #[test]
+    fn grep_keeps_the_newest_matches_by_room() {
+        let mut state = ServerState::default();
+        for (uid, room, text) in [(1, "b", "Hello"), (2, "a", "hello there"), (3, "b", "bye"), (4, "b", "oh hello")] {
+            let message = Message {
+                uid,
+                message: String::from(text),
+                ..Default::default()
+            };
+            state.rooms.entry(String::from(room)).or_default().push(message);
+        }
+
+        let uids = |rooms: &BTreeMap<String, Vec<Message>>| -> Vec<(String, Vec<u32>)> {
+            rooms.iter()
+                .map(|(room, messages)| (room.clone(), messages.iter().map(|message| message.uid).collect()))
+                .collect()
+        };
+        let (rooms, total) = state.grep("hello", 10);
+        assert_eq!(total, 3);
+        assert_eq!(uids(&rooms), [(String::from("a"), vec![2]), (String::from("b"), vec![1, 4])]);
+
+        let (rooms, total) = state.grep("hello", 2);
+        assert_eq!(total, 3);
+        assert_eq!(uids(&rooms), [(String::from("a"), vec![2]), (String::from("b"), vec![4])]);
     }
 }
@@ -0,0 +1,90 @@
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::Mutex,
+    time::{self, Instant},
+};
+
+use crate::ServerState;
+
+// Scrapers that take longer than this to send their request are dropped
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Largest request we read before answering anyway
+const MAX_REQUEST_SIZE: usize = 8192;
+
+// Answer every HTTP request on the listener with the current counters in
+// the Prometheus text format. Uptime counts from when this starts.
+pub async fn run(listener: TcpListener, state: Arc<Mutex<ServerState>>) {
+    let started = Instant::now();
+    loop {
+        let (stream, address) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(error) => {
+                warn!(%error, "Failed to accept metrics connection");
+                continue;
+            },
+        };
+
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(error) = respond(stream, &state, started).await {
+                info!(%address, %error, "Failed to serve metrics");
+            }
+        });
+    }
+}
+
+async fn respond(mut stream: TcpStream, state: &Mutex<ServerState>, started: Instant) -> io::Result<()> {
+    // What was asked for doesn't matter, but the request has to be read
+    // before answering or some clients see the connection reset
+    let mut request = Vec::new();
+    let mut buffer = [0; 1024];
+    let read_request = async {
+        while !request.ends_with(b"\r\n\r\n") && request.len() < MAX_REQUEST_SIZE {
+            let read = stream.read(&mut buffer).await?;
+            if read == 0 {
+                break;
+            }
+            request.extend_from_slice(&buffer[..read]);
+        }
+        io::Result::Ok(())
+    };
+    time::timeout(REQUEST_TIMEOUT, read_request)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "Request took too long"))??;
+
+    // Only copy the numbers out so chat handling isn't held up
+    let (users, messages) = {
+        let s = state.lock().await;
+        (s.user_list.len(), s.messages_sent)
+    };
+    let body = format!(
+        "# HELP chat_connected_users Clients connected right now\n\
+         # TYPE chat_connected_users gauge\n\
+         chat_connected_users {}\n\
+         # HELP chat_messages_total Room messages and whispers sent since the server started\n\
+         # TYPE chat_messages_total counter\n\
+         chat_messages_total {}\n\
+         # HELP chat_uptime_seconds Seconds since the server started\n\
+         # TYPE chat_uptime_seconds gauge\n\
+         chat_uptime_seconds {}\n",
+        users,
+        messages,
+        started.elapsed().as_secs(),
+    );
+    let response = format!(
+        "HTTP/1.1 200 OK\r\n\
+         Content-Type: text/plain; version=0.0.4\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        body.len(),
+        body,
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
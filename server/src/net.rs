@@ -0,0 +1,140 @@
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::debug;
+
+pub use common::{
+    FEATURE_DEFLATE, FEATURE_MSGPACK, HISTORY_PAGE, MAX_PACKET_SIZE, Message, PROTOCOL_VERSION, Packet, PacketType,
+    PacketWriter, RECEIPT_WINDOW,
+};
+
+// Every packet is sent as a 4-byte big-endian length prefix followed by
+// that many bytes of JSON or MessagePack, deflated if the prefix says so.
+pub async fn write_packet<W: AsyncWrite + Unpin>(writer: &mut PacketWriter<W>, packet: &Packet) -> io::Result<()> {
+    debug!(packet_type = ?packet.packet_type, "Sending packet");
+    let (prefix, data) = writer.encode(packet)?;
+    let inner = writer.get_mut();
+    inner.write_u32(prefix).await?;
+    inner.write_all(&data).await?;
+    inner.flush().await
+}
+
+// Returns an `UnexpectedEof` error once the peer has closed the connection
+pub async fn read_packet<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Packet> {
+    let prefix = reader.read_u32().await?;
+    let mut data = vec![0; common::frame_length(prefix)?];
+    reader.read_exact(&mut data).await?;
+    let packet = common::decode(prefix, &data)?;
+    debug!(packet_type = ?packet.packet_type, "Received packet");
+    Ok(packet)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    #[tokio::test]
+    async fn large_packet_survives_framing() {
+        // Bigger than the pipe buffer, so it arrives in several reads
+        let (mut client, mut server) = duplex(1024);
+        let packet = Packet {
+            packet_type: PacketType::NewMessage,
+            contents: "abcdefghij".repeat(1000),
+            ..Default::default()
+        };
+
+        let sent = packet.clone();
+        let writer = tokio::spawn(async move {
+            write_packet(&mut PacketWriter::new(&mut client), &sent).await.unwrap();
+        });
+        assert_eq!(read_packet(&mut server).await.unwrap(), packet);
+        writer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn back_to_back_packets_are_read_separately() {
+        let (client, mut server) = duplex(64 * 1024);
+        let mut client = PacketWriter::new(client);
+        let packets: Vec<Packet> = (0..3)
+            .map(|index| Packet {
+                packet_type: PacketType::NewMessage,
+                user_id: index,
+                contents: format!("message {}", index),
+                ..Default::default()
+            })
+            .collect();
+
+        for packet in &packets {
+            write_packet(&mut client, packet).await.unwrap();
+        }
+        for packet in &packets {
+            assert_eq!(&read_packet(&mut server).await.unwrap(), packet);
+        }
+    }
+
+    #[tokio::test]
+    async fn compressed_packets_round_trip() {
+        let (client, mut server) = duplex(64 * 1024);
+        let mut client = PacketWriter::new(client);
+        client.compress = true;
+        let packets = [
+            Packet {
+                packet_type: PacketType::NewMessage,
+                contents: "repetitive ".repeat(500),
+                ..Default::default()
+            },
+            // Too small to be worth compressing
+            Packet {
+                packet_type: PacketType::Ping,
+                ..Default::default()
+            },
+        ];
+
+        for packet in &packets {
+            write_packet(&mut client, packet).await.unwrap();
+        }
+        for packet in &packets {
+            assert_eq!(&read_packet(&mut server).await.unwrap(), packet);
+        }
+    }
+
+    #[tokio::test]
+    async fn bytes_after_a_frame_are_not_part_of_it() {
+        let (client, mut server) = duplex(1024);
+        let mut client = PacketWriter::new(client);
+        let packet = Packet {
+            packet_type: PacketType::UsernameChange,
+            contents: String::from("alice"),
+            ..Default::default()
+        };
+
+        write_packet(&mut client, &packet).await.unwrap();
+        client.get_mut().write_all(&[0; 512]).await.unwrap();
+        client.get_mut().write_all(b"garbage").await.unwrap();
+        assert_eq!(read_packet(&mut server).await.unwrap(), packet);
+    }
+
+    #[tokio::test]
+    async fn empty_frame_is_rejected() {
+        let (mut client, mut server) = duplex(64);
+        client.write_u32(0).await.unwrap();
+        let error = read_packet(&mut server).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn oversized_length_is_rejected() {
+        let (mut client, mut server) = duplex(64);
+        client.write_u32(common::MAX_PACKET_SIZE as u32 + 1).await.unwrap();
+        let error = read_packet(&mut server).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn closed_connection_is_eof() {
+        let (client, mut server) = duplex(64);
+        drop(client);
+        let error = read_packet(&mut server).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,121 @@
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use serde::{Serialize, Deserialize};
+use tracing::error;
+use tokio::{
+    fs,
+    io::AsyncWriteExt,
+    sync::{Mutex, Notify},
+};
+
+use crate::ServerState;
+use crate::net::Message;
+
+// Only one save may write the temporary file at a time
+static SAVE_LOCK: Mutex<()> = Mutex::const_new(());
+
+// Everything that survives a server restart. Connected users are not
+// saved since none of them are connected after a restart.
+#[derive(Default, Deserialize)]
+pub struct Snapshot {
+    pub rooms: HashMap<String, Vec<Message>>,
+    #[serde(default)]
+    pub trimmed_rooms: HashSet<String>,
+    #[serde(default)]
+    pub last_message_id: u32,
+}
+
+// Borrowed mirror of `Snapshot` so saving doesn't copy the history
+#[derive(Serialize)]
+struct SnapshotRef<'a> {
+    rooms: &'a HashMap<String, Vec<Message>>,
+    trimmed_rooms: &'a HashSet<String>,
+    last_message_id: u32,
+}
+
+// A missing data file just means there is nothing to restore yet
+pub fn load(path: &Path) -> io::Result<Snapshot> {
+    match std::fs::read(path) {
+        Ok(data) => Ok(serde_json::from_slice(&data)?),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(Snapshot::default()),
+        Err(error) => Err(error),
+    }
+}
+
+// Write the current state to disk. The snapshot goes to a temporary file
+// first and is then renamed over the old one, so a crash mid-write never
+// leaves a truncated data file behind.
+pub async fn save(path: &Path, state: &Mutex<ServerState>) -> io::Result<()> {
+    let _guard = SAVE_LOCK.lock().await;
+    let data = {
+        let s = state.lock().await;
+        serde_json::to_vec(&SnapshotRef {
+            rooms: &s.rooms,
+            trimmed_rooms: &s.trimmed_rooms,
+            last_message_id: s.last_message_id,
+        })?
+    };
+
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let temp_path = PathBuf::from(temp_path);
+
+    let mut file = fs::File::create(&temp_path).await?;
+    file.write_all(&data).await?;
+    file.sync_all().await?;
+    fs::rename(&temp_path, path).await
+}
+
+// Save whenever the state is marked dirty. Changes that happen while a
+// save is in progress are picked up by the next one. Once `shutdown` is
+// notified everything is saved one last time and the task ends.
+pub async fn run(path: PathBuf, state: Arc<Mutex<ServerState>>, shutdown: Arc<Notify>) -> io::Result<()> {
+    let dirty = state.lock().await.dirty.clone();
+    loop {
+        tokio::select! {
+            _ = dirty.notified() => {
+                if let Err(error) = save(&path, &state).await {
+                    error!(path = %path.display(), %error, "Failed to save history");
+                }
+            },
+            _ = shutdown.notified() => return save(&path, &state).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DEFAULT_ROOM;
+
+    #[tokio::test]
+    async fn shutdown_flushes_the_history() {
+        let path = std::env::temp_dir().join(format!("rustchat-persist-test-{}.json", std::process::id()));
+        let state = Arc::new(Mutex::new(ServerState::default()));
+        let shutdown = Arc::new(Notify::new());
+
+        // Stored without marking the state dirty, so only the save made on
+        // shutdown can write it
+        let _ = std::fs::remove_file(&path);
+        {
+            let mut s = state.lock().await;
+            let uid = s.new_message_id();
+            let message = Message {
+                uid,
+                message: String::from("last words"),
+                ..Default::default()
+            };
+            s.store_message(DEFAULT_ROOM, message, None);
+        }
+        shutdown.notify_one();
+        run(path.clone(), state, shutdown).await.unwrap();
+
+        let snapshot = load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let messages: Vec<&str> = snapshot.rooms[DEFAULT_ROOM].iter().map(|message| message.message.as_str()).collect();
+        assert_eq!(messages, ["last words"]);
+        assert_eq!(snapshot.last_message_id, 1);
+    }
+}